@@ -7,15 +7,32 @@ use andromeda_std::{
 #[cfg(not(feature = "library"))]
 use cosmwasm_std::entry_point;
 use cosmwasm_std::{ensure, Binary, Deps, DepsMut, Env, MessageInfo, Response};
-use cw2::set_contract_version;
+use cw2::{get_contract_version, set_contract_version};
 
 use crate::{
-    execute::{add_condition, add_variable},
-    msg::{ExecuteMsg, InstantiateMsg, QueryMsg},
+    dependencies::{
+        save_variable_dependencies, transitive_condition_dependencies, transitive_dependencies,
+    },
+    error::ContractError as ConditionalError,
+    evaluation::Evaluation,
+    execute::{
+        add_condition, add_trigger, add_variable, apply_condition, cancel_proposal, deposit, fire,
+        forward, freeze, freeze_all, propose_condition, refund, release, remove_trigger,
+        set_limits, set_proxy, set_timelock, validate_condition, validate_limits,
+        validate_variable,
+    },
+    migrations::{migrate_state, parse_version, record_condition_history},
+    msg::{ExecuteMsg, InstantiateMsg, MigrateMsg, QueryMsg},
     query::{
-        create_condition_ctx, evaluate_condition, evaluate_default_condition, evaluate_token,
-        evaluate_variable,
+        apply_overrides, condition_history, condition_status, create_condition_ctx, evaluate_batch,
+        evaluate_condition_version, evaluate_condition_with, evaluate_stored_condition_with,
+        evaluate_token_with, evaluate_variable, explain_stored_condition, frozen, list_fireable,
+    },
+    state::{
+        is_frozen, load_condition, save_condition, CONDITIONS, ESCROWS, LIMITS, PROXY, TIMELOCK,
+        TRIGGERS, VARIABLES,
     },
+    validation::{condition_diagnostics, variable_diagnostics},
 };
 
 // version info for migration info
@@ -34,7 +51,7 @@ pub fn instantiate(
 
     let resp = contract.instantiate(
         deps.storage,
-        env,
+        env.clone(),
         deps.api,
         info.clone(),
         BaseInstantiateMsg {
@@ -46,6 +63,44 @@ pub fn instantiate(
         },
     )?;
 
+    // Any invalid entry fails instantiation, so the initial state is saved all or nothing.
+    if let Some(limits) = msg.limits {
+        validate_limits(&limits)?;
+        LIMITS.save(deps.storage, &limits)?;
+    }
+    if let Some(timelock) = msg.timelock {
+        TIMELOCK.save(deps.storage, &timelock)?;
+    }
+    // Variables are saved in order and can only depend on the ones before them.
+    for (name, variable) in msg.variables {
+        validate_variable(&deps.as_ref(), &name, &variable)?;
+        ensure!(
+            !VARIABLES.has(deps.storage, &name),
+            ConditionalError::DuplicateName { name }
+        );
+        save_variable_dependencies(deps.storage, &name, &variable)?;
+        VARIABLES.save(deps.storage, &name, &variable)?;
+    }
+    if let Some(condition) = msg.condition {
+        validate_condition(&deps.as_ref(), &condition)?;
+        save_condition(deps.storage, &env.block, &info.sender, None, &condition)?;
+    }
+    for (name, condition) in msg.conditions {
+        ensure!(!name.is_empty(), ConditionalError::EmptyName {});
+        validate_condition(&deps.as_ref(), &condition)?;
+        ensure!(
+            !CONDITIONS.has(deps.storage, &name),
+            ConditionalError::DuplicateName { name }
+        );
+        save_condition(
+            deps.storage,
+            &env.block,
+            &info.sender,
+            Some(&name),
+            &condition,
+        )?;
+    }
+
     Ok(resp
         .add_attribute("method", "instantiate")
         .add_attribute("owner", info.sender))
@@ -80,30 +135,184 @@ pub fn handle_execute(ctx: ExecuteContext, msg: ExecuteMsg) -> Result<Response,
     );
 
     match msg {
-        ExecuteMsg::AddCondition { condition } => add_condition(ctx, condition),
+        ExecuteMsg::AddCondition { condition, name } => add_condition(ctx, condition, name),
         ExecuteMsg::AddVariable { variable, name } => add_variable(ctx, &variable, &name),
+        ExecuteMsg::SetProxy {
+            target,
+            allowed_msgs,
+        } => set_proxy(ctx, target, allowed_msgs),
+        ExecuteMsg::Forward { msg, funds } => forward(ctx, msg, funds),
+        ExecuteMsg::Deposit {
+            recipient,
+            condition_name,
+            refund_condition_name,
+            expiry,
+        } => deposit(
+            ctx,
+            recipient,
+            condition_name,
+            refund_condition_name,
+            expiry,
+        ),
+        ExecuteMsg::Release { id } => release(ctx, id),
+        ExecuteMsg::Refund { id } => refund(ctx, id),
+        ExecuteMsg::AddTrigger { trigger } => add_trigger(ctx, trigger),
+        ExecuteMsg::RemoveTrigger { trigger_id } => remove_trigger(ctx, trigger_id),
+        ExecuteMsg::Fire { trigger_id } => fire(ctx, trigger_id),
+        ExecuteMsg::SetLimits { limits } => set_limits(ctx, limits),
+        ExecuteMsg::SetTimelock { delay } => set_timelock(ctx, delay),
+        ExecuteMsg::ProposeCondition { condition, name } => propose_condition(ctx, condition, name),
+        ExecuteMsg::ApplyCondition { name } => apply_condition(ctx, name),
+        ExecuteMsg::CancelProposal { name } => cancel_proposal(ctx, name),
+        ExecuteMsg::Freeze { name } => freeze(ctx, name),
+        ExecuteMsg::FreezeAll {} => freeze_all(ctx),
         _ => ADOContract::default().execute(ctx, msg),
     }
 }
 
+#[cfg_attr(not(feature = "library"), entry_point)]
+pub fn migrate(deps: DepsMut, env: Env, _msg: MigrateMsg) -> Result<Response, ContractError> {
+    let stored = get_contract_version(deps.storage)?;
+    ensure!(
+        stored.contract == CONTRACT_NAME,
+        ConditionalError::WrongContract {
+            contract: stored.contract
+        }
+    );
+    let stored_version = parse_version(&stored.version)?;
+    ensure!(
+        stored_version <= parse_version(CONTRACT_VERSION)?,
+        ConditionalError::MigrationDowngrade {
+            stored: stored.version,
+            current: CONTRACT_VERSION.to_string()
+        }
+    );
+
+    migrate_state(deps.storage, &stored_version)?;
+    record_condition_history(deps.storage, &env.block, &env.contract.address)?;
+    set_contract_version(deps.storage, CONTRACT_NAME, CONTRACT_VERSION)?;
+
+    Ok(Response::new()
+        .add_attribute("method", "migrate")
+        .add_attribute("previous_version", stored.version)
+        .add_attribute("version", CONTRACT_VERSION))
+}
+
 #[cfg_attr(not(feature = "library"), entry_point)]
 pub fn query(deps: Deps, env: Env, msg: QueryMsg) -> Result<Binary, ContractError> {
     match msg {
-        QueryMsg::Evaluate { ctx } => {
+        QueryMsg::Evaluate {
+            name,
+            ctx,
+            overrides,
+        } => {
+            let mut condition_ctx = create_condition_ctx(env, ctx);
+            let mut eval = Evaluation::new(deps.storage)?;
+            apply_overrides(&deps, &mut condition_ctx, &mut eval, overrides)?;
+            encode_binary(&evaluate_stored_condition_with(
+                &deps,
+                &condition_ctx,
+                &mut eval,
+                name.as_deref(),
+            )?)
+        }
+        QueryMsg::EvaluateCondition {
+            condition,
+            ctx,
+            overrides,
+        } => {
+            let mut condition_ctx = create_condition_ctx(env, ctx);
+            let mut eval = Evaluation::new(deps.storage)?;
+            apply_overrides(&deps, &mut condition_ctx, &mut eval, overrides)?;
+            encode_binary(&evaluate_condition_with(
+                &deps,
+                &condition_ctx,
+                &mut eval,
+                condition,
+            )?)
+        }
+        QueryMsg::EvaluateVariable {
+            name,
+            ctx,
+            overrides,
+        } => {
+            let mut condition_ctx = create_condition_ctx(env, ctx);
+            let mut eval = Evaluation::new(deps.storage)?;
+            apply_overrides(&deps, &mut condition_ctx, &mut eval, overrides)?;
+            encode_binary(&evaluate_token_with(
+                &deps,
+                &condition_ctx,
+                &mut eval,
+                &name,
+            )?)
+        }
+        QueryMsg::EvaluateCustomVariable { variable, ctx } => {
             let condition_ctx = create_condition_ctx(env, ctx);
-            encode_binary(&evaluate_default_condition(&deps, &condition_ctx))
+            encode_binary(&evaluate_variable(&deps, &condition_ctx, &variable)?)
+        }
+        QueryMsg::Proxy {} => encode_binary(&PROXY.may_load(deps.storage)?),
+        QueryMsg::Escrow { id } => encode_binary(&ESCROWS.load(deps.storage, id)?),
+        QueryMsg::Trigger { trigger_id } => {
+            encode_binary(&TRIGGERS.load(deps.storage, trigger_id)?)
         }
-        QueryMsg::EvaluateCondition { condition, ctx } => {
+        QueryMsg::ListFireable { start_after, limit } => {
+            encode_binary(&list_fireable(&deps, env, start_after, limit)?)
+        }
+        QueryMsg::Dependencies { name } => {
+            encode_binary(&transitive_dependencies(deps.storage, &name)?)
+        }
+        QueryMsg::ConditionDependencies { name } => {
+            let condition = load_condition(deps.storage, name.as_deref())?;
+            encode_binary(&transitive_condition_dependencies(
+                deps.storage,
+                &condition,
+            )?)
+        }
+        QueryMsg::Explain { name, ctx } => {
             let condition_ctx = create_condition_ctx(env, ctx);
-            encode_binary(&evaluate_condition(&deps, &condition_ctx, condition))
+            encode_binary(&explain_stored_condition(
+                &deps,
+                &condition_ctx,
+                name.as_deref(),
+            )?)
         }
-        QueryMsg::EvaluateVariable { name, ctx } => {
+        QueryMsg::EvaluateBatch { items, ctx } => {
             let condition_ctx = create_condition_ctx(env, ctx);
-            encode_binary(&evaluate_token(&deps, &condition_ctx, &name))
+            encode_binary(&evaluate_batch(&deps, &condition_ctx, items)?)
         }
-        QueryMsg::EvaluateCustomVariable { variable, ctx } => {
+        QueryMsg::EvaluateAt { name, version, ctx } => {
             let condition_ctx = create_condition_ctx(env, ctx);
-            encode_binary(&evaluate_variable(&deps, &condition_ctx, &variable))
+            encode_binary(&evaluate_condition_version(
+                &deps,
+                &condition_ctx,
+                name.as_deref(),
+                version,
+            )?)
+        }
+        QueryMsg::ConditionHistory {
+            name,
+            start_after,
+            limit,
+        } => encode_binary(&condition_history(
+            &deps,
+            name.as_deref(),
+            start_after,
+            limit,
+        )?),
+        QueryMsg::ConditionStatus { name } => {
+            encode_binary(&condition_status(&deps, name.as_deref())?)
+        }
+        QueryMsg::Timelock {} => encode_binary(&TIMELOCK.may_load(deps.storage)?),
+        QueryMsg::IsFrozen { name } => encode_binary(&is_frozen(deps.storage, name.as_deref())?),
+        QueryMsg::Frozen { start_after, limit } => {
+            encode_binary(&frozen(&deps, start_after, limit)?)
+        }
+        QueryMsg::Limits {} => encode_binary(&LIMITS.may_load(deps.storage)?.unwrap_or_default()),
+        QueryMsg::ValidateCondition { condition } => {
+            encode_binary(&condition_diagnostics(&deps, &condition))
+        }
+        QueryMsg::ValidateVariable { variable } => {
+            encode_binary(&variable_diagnostics(&deps, &variable))
         }
         _ => ADOContract::default().query(deps, env, msg),
     }
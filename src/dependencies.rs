@@ -0,0 +1,242 @@
+use std::collections::BTreeSet;
+
+use cosmwasm_std::{ensure, Int128, Order, StdResult, Storage};
+
+use crate::{
+    error::ContractError,
+    packages::eval::eval::{is_operator, Tokens},
+    state::{CONDITION, CONDITIONS, DEPENDENCIES, PENDING_CONDITIONS, VARIABLES},
+    template::{template_placeholders, Placeholder},
+    types::{Condition, ConditionWing, ExternalQueryMsg, Variable},
+};
+
+/// Names of the stored variables an expression reads. A single token that is not a
+/// stored variable is a literal, in longer expressions every operand that is not a
+/// number has to be a variable.
+pub fn expression_dependencies(storage: &dyn Storage, tokens: &Tokens) -> Vec<String> {
+    if let [token] = tokens.as_slice() {
+        if VARIABLES.has(storage, token) {
+            return vec![token.clone()];
+        }
+        return vec![];
+    }
+    operand_names(tokens)
+}
+
+/// Operands of an expression that are not numbers
+fn operand_names(tokens: &Tokens) -> Vec<String> {
+    tokens
+        .iter()
+        .filter(|token| !is_operator(token) && token.parse::<Int128>().is_err())
+        .cloned()
+        .collect()
+}
+
+pub fn variable_dependencies(storage: &dyn Storage, variable: &Variable) -> Vec<String> {
+    match variable {
+        Variable::Raw(_) | Variable::Reference(_) => vec![],
+        Variable::Query(query) => {
+            let mut dependencies = variable_dependencies(storage, &query.contract);
+            match &query.query {
+                ExternalQueryMsg::Template(msg) => {
+                    // Malformed templates are rejected when the variable is validated
+                    let placeholders = template_placeholders(&msg.msg).unwrap_or_default();
+                    dependencies.extend(placeholders.into_iter().filter_map(|(_, placeholder)| {
+                        match placeholder {
+                            Placeholder::Var(name) => Some(name),
+                            Placeholder::Ref(_) => None,
+                        }
+                    }));
+                }
+                ExternalQueryMsg::Storage(msg) => dependencies.extend(
+                    msg.keys
+                        .iter()
+                        .flat_map(|key| variable_dependencies(storage, &key.value)),
+                ),
+                ExternalQueryMsg::Raw(_) | ExternalQueryMsg::Smart(_) => {}
+            }
+            dependencies
+        }
+        // Literals are written as `Variable::Raw`, so unlike in conditions every operand
+        // of a variable expression that is not a number names a variable, even one that
+        // is not defined yet
+        Variable::Expression(tokens) => operand_names(tokens),
+        Variable::Coalesce(variables) => variables
+            .iter()
+            .flat_map(|variable| variable_dependencies(storage, variable))
+            .collect(),
+        Variable::WithDefault { variable, .. } => variable_dependencies(storage, variable),
+        Variable::Balance { address, .. } | Variable::AllBalances { address } => {
+            variable_dependencies(storage, address)
+        }
+        Variable::Supply { .. } => vec![],
+        Variable::Cw20Balance { token, address } => [token, address]
+            .iter()
+            .flat_map(|variable| variable_dependencies(storage, variable))
+            .collect(),
+        Variable::Cw20Allowance {
+            token,
+            owner,
+            spender,
+        } => [token, owner, spender]
+            .iter()
+            .flat_map(|variable| variable_dependencies(storage, variable))
+            .collect(),
+        Variable::Cw20TokenInfo { token, .. } => variable_dependencies(storage, token),
+        Variable::Cw721OwnerOf {
+            collection,
+            token_id,
+        } => [collection, token_id]
+            .iter()
+            .flat_map(|variable| variable_dependencies(storage, variable))
+            .collect(),
+        Variable::Cw721Tokens { collection, owner } => [collection, owner]
+            .iter()
+            .flat_map(|variable| variable_dependencies(storage, variable))
+            .collect(),
+        Variable::Cw721IsApproved {
+            collection,
+            token_id,
+            spender,
+        } => [collection, token_id, spender]
+            .iter()
+            .flat_map(|variable| variable_dependencies(storage, variable))
+            .collect(),
+        Variable::Delegation {
+            delegator,
+            validator,
+        } => [delegator, validator]
+            .iter()
+            .flat_map(|variable| variable_dependencies(storage, variable))
+            .collect(),
+        Variable::TotalDelegated { delegator } => variable_dependencies(storage, delegator),
+        Variable::BondedDenom => vec![],
+        Variable::IsActiveValidator { validator } => variable_dependencies(storage, validator),
+    }
+}
+
+pub fn condition_dependencies(storage: &dyn Storage, condition: &Condition) -> Vec<String> {
+    let mut dependencies = BTreeSet::new();
+    for wing in [&condition.left, &condition.right] {
+        match wing {
+            ConditionWing::Expression(tokens) => {
+                dependencies.extend(expression_dependencies(storage, tokens))
+            }
+            ConditionWing::Condition(condition) => {
+                dependencies.extend(condition_dependencies(storage, condition))
+            }
+            _ => {}
+        }
+    }
+    dependencies.into_iter().collect()
+}
+
+/// Every variable `name` depends on, directly or through other variables
+pub fn transitive_dependencies(storage: &dyn Storage, name: &str) -> StdResult<BTreeSet<String>> {
+    let mut visited = BTreeSet::new();
+    let mut pending = vec![name.to_string()];
+    while let Some(current) = pending.pop() {
+        for dependency in DEPENDENCIES
+            .may_load(storage, &current)?
+            .unwrap_or_default()
+        {
+            if visited.insert(dependency.clone()) {
+                pending.push(dependency);
+            }
+        }
+    }
+    Ok(visited)
+}
+
+/// Every variable a condition depends on, directly or through other variables
+pub fn transitive_condition_dependencies(
+    storage: &dyn Storage,
+    condition: &Condition,
+) -> StdResult<BTreeSet<String>> {
+    let mut dependencies = BTreeSet::new();
+    for name in condition_dependencies(storage, condition) {
+        dependencies.extend(transitive_dependencies(storage, &name)?);
+        dependencies.insert(name);
+    }
+    Ok(dependencies)
+}
+
+/// Every operand of a condition that is not a number, whether or not it names a
+/// variable yet. Defining one changes what the condition evaluates to.
+pub fn condition_tokens(condition: &Condition) -> BTreeSet<String> {
+    let mut tokens = BTreeSet::new();
+    for wing in [&condition.left, &condition.right] {
+        match wing {
+            ConditionWing::Expression(expression) => tokens.extend(operand_names(expression)),
+            ConditionWing::Condition(condition) => tokens.extend(condition_tokens(condition)),
+            _ => {}
+        }
+    }
+    tokens
+}
+
+/// Every name a condition reads: its operands and the variables they depend on
+pub fn condition_names(
+    storage: &dyn Storage,
+    condition: &Condition,
+) -> StdResult<BTreeSet<String>> {
+    let mut names = transitive_condition_dependencies(storage, condition)?;
+    names.extend(condition_tokens(condition));
+    Ok(names)
+}
+
+/// Every name the stored and pending conditions read
+pub fn stored_condition_names(storage: &dyn Storage) -> StdResult<BTreeSet<String>> {
+    let mut conditions = CONDITIONS
+        .range(storage, None, None, Order::Ascending)
+        .map(|item| item.map(|(_, condition)| condition))
+        .collect::<StdResult<Vec<_>>>()?;
+    conditions.extend(CONDITION.may_load(storage)?);
+    for item in PENDING_CONDITIONS.range(storage, None, None, Order::Ascending) {
+        conditions.push(item?.1.condition);
+    }
+    let mut names = BTreeSet::new();
+    for condition in conditions {
+        names.extend(condition_names(storage, &condition)?);
+    }
+    Ok(names)
+}
+
+/// Stores the direct dependencies of a variable, rejecting undefined names and cycles
+pub fn save_variable_dependencies(
+    storage: &mut dyn Storage,
+    name: &str,
+    variable: &Variable,
+) -> Result<(), ContractError> {
+    let dependencies = variable_dependencies(storage, variable);
+    for dependency in &dependencies {
+        ensure!(
+            dependency != name && !transitive_dependencies(storage, dependency)?.contains(name),
+            ContractError::DependencyCycle {
+                name: name.to_string()
+            }
+        );
+        ensure!(
+            VARIABLES.has(storage, dependency),
+            ContractError::UndefinedName {
+                name: dependency.clone()
+            }
+        );
+    }
+    DEPENDENCIES.save(storage, name, &dependencies)?;
+    Ok(())
+}
+
+/// Conditions are never referenced by name, so only undefined names need to be rejected
+pub fn check_condition_dependencies(
+    storage: &dyn Storage,
+    condition: &Condition,
+) -> Result<(), ContractError> {
+    for dependency in condition_dependencies(storage, condition) {
+        ensure!(
+            VARIABLES.has(storage, &dependency),
+            ContractError::UndefinedName { name: dependency }
+        );
+    }
+    Ok(())
+}
@@ -1,4 +1,5 @@
-use cosmwasm_std::StdError;
+use andromeda_std::error::ContractError as AndrContractError;
+use cosmwasm_std::{StdError, Timestamp};
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -12,4 +13,130 @@ pub enum ContractError {
     // Look at https://docs.rs/thiserror/1.0.21/thiserror/ for details.
     #[error("Generic")]
     Generic {},
+
+    #[error("Name cannot be empty")]
+    EmptyName {},
+
+    #[error("{name} reads as a number or an operator and cannot be used as a name")]
+    InvalidName { name: String },
+
+    #[error("{name} is defined more than once")]
+    DuplicateName { name: String },
+
+    #[error("{name} is not defined")]
+    UndefinedName { name: String },
+
+    #[error("Saving {name} would create a dependency cycle")]
+    DependencyCycle { name: String },
+
+    #[error("Expression cannot be empty")]
+    EmptyExpression {},
+
+    #[error("Cannot migrate from contract {contract}")]
+    WrongContract { contract: String },
+
+    #[error("Cannot migrate from version {stored} to older version {current}")]
+    MigrationDowngrade { stored: String, current: String },
+
+    #[error("Invalid operator for non integer types")]
+    InvalidOperator {},
+
+    #[error("Invalid expression: {msg}")]
+    InvalidExpression { msg: String },
+
+    #[error("{name} resolved to {value}, which is not a number")]
+    NotANumber { name: String, value: String },
+
+    #[error("Variable does not resolve to an address")]
+    InvalidAddress {},
+
+    #[error("Variable does not resolve to a value")]
+    MissingValue {},
+
+    #[error("Invalid query template: {msg}")]
+    InvalidTemplate { msg: String },
+
+    #[error("{value} is not a valid {key_type} storage key")]
+    InvalidStorageKey { key_type: String, value: String },
+
+    #[error("Raw data cannot be decoded as {decode}")]
+    InvalidRawData { decode: String },
+
+    #[error("Invalid path {path}: {msg}")]
+    InvalidPath { path: String, msg: String },
+
+    #[error("Evaluation exceeded the limit of {max} {limit}")]
+    LimitExceeded { limit: String, max: u32 },
+
+    #[error("Cannot override {key}")]
+    InvalidOverride { key: String },
+
+    #[error("Limits must be greater than zero")]
+    InvalidLimits {},
+
+    #[error("Query to {contract} failed: {msg}")]
+    ExternalQueryFailed { contract: String, msg: String },
+
+    #[error("Coalesce needs at least one variable")]
+    EmptyCoalesce {},
+
+    #[error("Condition not met")]
+    ConditionNotMet {},
+
+    #[error("Proxy is not configured")]
+    ProxyNotConfigured {},
+
+    #[error("Message type {msg_type} is not allowed to be forwarded")]
+    ForwardNotAllowed { msg_type: String },
+
+    #[error("Forwarded message must be a json object with a single message type")]
+    InvalidForwardMsg {},
+
+    #[error("Forwarded funds must equal the funds sent")]
+    FundsMismatch {},
+
+    #[error("No funds sent")]
+    NoFunds {},
+
+    #[error("Condition {name} does not exist")]
+    ConditionNotFound { name: String },
+
+    #[error("Escrow has expired")]
+    EscrowExpired {},
+
+    #[error("Trigger balance cannot cover the reward and the funds its messages send")]
+    InsufficientTriggerBalance {},
+
+    #[error("Escrow can only be refunded after expiry or when its refund condition holds")]
+    RefundNotAllowed {},
+
+    #[error("{name} is frozen")]
+    Frozen { name: String },
+
+    #[error("Conditions can only be changed through a proposal while a timelock is set")]
+    TimelockActive {},
+
+    #[error("{name} is used by a condition and cannot change while a timelock is set")]
+    TimelockedVariable { name: String },
+
+    #[error("The timelock cannot be decreased")]
+    TimelockDecrease {},
+
+    #[error("No condition change is pending")]
+    NoPendingCondition {},
+
+    #[error("Proposal can only be applied from {effective}")]
+    ProposalNotReady { effective: Timestamp },
+}
+
+/// Entry points and ADO handlers work with the andromeda error, so contract specific
+/// errors are surfaced through it
+impl From<ContractError> for AndrContractError {
+    fn from(err: ContractError) -> Self {
+        match err {
+            ContractError::Std(err) => AndrContractError::Std(err),
+            ContractError::Unauthorized {} => AndrContractError::Unauthorized {},
+            err => AndrContractError::Std(StdError::generic_err(err.to_string())),
+        }
+    }
 }
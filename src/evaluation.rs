@@ -0,0 +1,205 @@
+use std::collections::HashMap;
+
+use cosmwasm_std::{
+    to_vec, Addr, Binary, Deps, Empty, QueryRequest, StdError, StdResult, Storage, WasmQuery,
+};
+use serde_cw_value::Value;
+
+use crate::{error::ContractError, state::LIMITS, types::EvaluationLimits};
+
+/// State of a single evaluation, shared by every condition, variable and query resolved
+/// while answering one request. Each named variable and each unique external query is
+/// only resolved once. The evaluation fails as soon as it goes over one of the limits.
+pub struct Evaluation {
+    limits: EvaluationLimits,
+    depth: u32,
+    steps: u32,
+    /// External queries counted against the limit since it was last reset
+    limited_queries: u32,
+    overrides: HashMap<String, Value>,
+    variables: HashMap<String, Option<Value>>,
+    /// Variable names in the order they were first resolved
+    resolved: Vec<String>,
+    queries: HashMap<Vec<u8>, Option<Value>>,
+    pub cache_hits: u64,
+    pub external_queries: u64,
+}
+
+impl Evaluation {
+    pub fn new(storage: &dyn Storage) -> StdResult<Self> {
+        Ok(Self {
+            limits: LIMITS.may_load(storage)?.unwrap_or_default(),
+            depth: 0,
+            steps: 0,
+            limited_queries: 0,
+            overrides: HashMap::new(),
+            variables: HashMap::new(),
+            resolved: vec![],
+            queries: HashMap::new(),
+            cache_hits: 0,
+            external_queries: 0,
+        })
+    }
+
+    /// Called before evaluating a condition or variable, every call is a step and nests
+    /// one level deeper until the matching `exit`
+    pub fn enter(&mut self) -> Result<(), ContractError> {
+        self.steps += 1;
+        check_limit("steps", self.steps, self.limits.max_steps)?;
+        // Checked before entering, a failed `enter` has no matching `exit`
+        check_limit("nested levels", self.depth + 1, self.limits.max_depth)?;
+        self.depth += 1;
+        Ok(())
+    }
+
+    pub fn exit(&mut self) {
+        self.depth -= 1;
+    }
+
+    /// Gives an independent evaluation that shares this one's cache the full limits again
+    pub fn reset_limits(&mut self) {
+        self.depth = 0;
+        self.steps = 0;
+        self.limited_queries = 0;
+    }
+
+    pub fn check_expression(&self, tokens: usize) -> Result<(), ContractError> {
+        check_limit(
+            "expression tokens",
+            tokens as u32,
+            self.limits.max_expression_tokens,
+        )
+    }
+
+    /// Counts a query that goes to the querier
+    fn count_external_query(&mut self) -> Result<(), ContractError> {
+        self.external_queries += 1;
+        self.limited_queries += 1;
+        check_limit(
+            "external queries",
+            self.limited_queries,
+            self.limits.max_external_queries,
+        )
+    }
+
+    /// Makes a named variable resolve to `value` without evaluating it
+    pub fn set_override(&mut self, name: &str, value: Value) {
+        self.overrides.insert(name.to_string(), value);
+    }
+
+    pub fn overridden(&self, name: &str) -> Option<Value> {
+        self.overrides.get(name).cloned()
+    }
+
+    /// The value of a named variable when it was already resolved
+    pub fn variable(&mut self, name: &str) -> Option<Option<Value>> {
+        let value = self.variables.get(name).cloned();
+        if value.is_some() {
+            self.cache_hits += 1;
+        }
+        value
+    }
+
+    pub fn set_variable(&mut self, name: &str, value: Option<Value>) {
+        if self.variables.insert(name.to_string(), value).is_none() {
+            self.resolved.push(name.to_string());
+        }
+    }
+
+    /// Resolved variables in the order they were first resolved
+    pub fn resolved_variables(&self) -> impl Iterator<Item = (&str, Option<&Value>)> {
+        self.resolved
+            .iter()
+            .map(|name| (name.as_str(), self.variables[name].as_ref()))
+    }
+
+    pub fn smart_query(
+        &mut self,
+        deps: &Deps,
+        contract: &Addr,
+        msg: Binary,
+    ) -> Result<Value, ContractError> {
+        let request = QueryRequest::Wasm(WasmQuery::Smart {
+            contract_addr: contract.to_string(),
+            msg,
+        });
+        let value = self.cached_query(&request, || {
+            deps.querier
+                .query::<Value>(&request)
+                .map(Some)
+                .map_err(|err| query_failed(contract, err))
+        })?;
+        Ok(value.unwrap_or(Value::Unit))
+    }
+
+    /// Raw storage value of another contract, `None` when the key is not set
+    pub fn raw_query(
+        &mut self,
+        deps: &Deps,
+        contract: &Addr,
+        key: Binary,
+    ) -> Result<Option<Vec<u8>>, ContractError> {
+        let request = QueryRequest::Wasm(WasmQuery::Raw {
+            contract_addr: contract.to_string(),
+            key: key.clone(),
+        });
+        let value = self.cached_query(&request, || {
+            let data = deps
+                .querier
+                .query_wasm_raw(contract, key)
+                .map_err(|err| query_failed(contract, err))?;
+            Ok(data.map(Value::Bytes))
+        })?;
+        match value {
+            Some(Value::Bytes(data)) => Ok(Some(data)),
+            _ => Ok(None),
+        }
+    }
+
+    /// Bank or staking query, cached like the queries to other contracts
+    pub fn chain_query(
+        &mut self,
+        deps: &Deps,
+        request: QueryRequest<Empty>,
+    ) -> Result<Value, ContractError> {
+        let value = self.cached_query(&request, || {
+            Ok(Some(deps.querier.query::<Value>(&request)?))
+        })?;
+        Ok(value.unwrap_or(Value::Unit))
+    }
+
+    fn cached_query(
+        &mut self,
+        request: &QueryRequest<Empty>,
+        query: impl FnOnce() -> Result<Option<Value>, ContractError>,
+    ) -> Result<Option<Value>, ContractError> {
+        let key = to_vec(request)?;
+        if let Some(value) = self.queries.get(&key) {
+            self.cache_hits += 1;
+            return Ok(value.clone());
+        }
+        self.count_external_query()?;
+        // Failed queries are not cached, they fail the evaluation or are skipped by a
+        // coalesce
+        let value = query()?;
+        self.queries.insert(key, value.clone());
+        Ok(value)
+    }
+}
+
+fn query_failed(contract: &Addr, err: StdError) -> ContractError {
+    ContractError::ExternalQueryFailed {
+        contract: contract.to_string(),
+        msg: err.to_string(),
+    }
+}
+
+fn check_limit(limit: &str, value: u32, max: u32) -> Result<(), ContractError> {
+    if value > max {
+        return Err(ContractError::LimitExceeded {
+            limit: limit.to_string(),
+            max,
+        });
+    }
+    Ok(())
+}
@@ -1,26 +1,600 @@
-use andromeda_std::{common::context::ExecuteContext, error::ContractError};
-use cosmwasm_std::Response;
+use andromeda_std::{
+    ado_contract::{permissioning::is_context_permissioned, ADOContract},
+    common::context::ExecuteContext,
+    error::ContractError,
+};
+use cosmwasm_std::{
+    ensure, from_slice, to_binary, Addr, BankMsg, Binary, Coin, CosmosMsg, Deps, Empty, Int128,
+    Response, StakingMsg, StdError, Storage, Uint128, WasmMsg,
+};
+use cw_utils::Expiration;
+use serde_cw_value::Value;
+use std::collections::{BTreeMap, BTreeSet};
 
 use crate::{
-    state::{CONDITION, VARIABLES},
-    types::{Condition, Variable},
+    dependencies::{
+        check_condition_dependencies, condition_names, save_variable_dependencies,
+        stored_condition_names, transitive_dependencies,
+    },
+    error::ContractError as ConditionalError,
+    msg::ExecuteMsg,
+    packages::eval::eval::is_operator,
+    query::{create_verified_condition_ctx, evaluate_condition, original_sender},
+    state::{
+        is_frozen, save_condition, ALL_FROZEN, CONDITION, CONDITIONS, ESCROWS, FROZEN, LIMITS,
+        NEXT_ESCROW_ID, NEXT_TRIGGER_ID, PENDING_CONDITIONS, PROXY, TIMELOCK, TRIGGERS, VARIABLES,
+    },
+    types::{
+        Condition, Escrow, EvaluationLimits, PendingCondition, ProxyConfig, Trigger, TriggerInfo,
+        Variable,
+    },
+    validation::{check_condition_valid, check_variable_valid},
 };
 
-pub fn add_condition(ctx: ExecuteContext, condition: Condition) -> Result<Response, ContractError> {
-    CONDITION.save(ctx.deps.storage, &condition)?;
+/// Checks a variable before it is stored, failing on anything `ValidateVariable` reports
+pub fn validate_variable(
+    deps: &Deps,
+    name: &str,
+    variable: &Variable,
+) -> Result<(), ContractError> {
+    ensure!(!name.is_empty(), ConditionalError::EmptyName {});
+    // Expressions read such names as literals and operators, never as the variable
+    ensure!(
+        name.parse::<Int128>().is_err() && !is_operator(name),
+        ConditionalError::InvalidName {
+            name: name.to_string()
+        }
+    );
+    check_variable_valid(deps, variable)?;
+    Ok(())
+}
+
+/// Checks a condition before it is stored, failing on anything `ValidateCondition` reports
+pub fn validate_condition(deps: &Deps, condition: &Condition) -> Result<(), ContractError> {
+    check_condition_valid(deps, condition)?;
+    Ok(())
+}
+
+/// Checks a condition before it is set or proposed under `name`
+fn validate_condition_change(
+    deps: &Deps,
+    condition: &Condition,
+    name: Option<&str>,
+) -> Result<(), ContractError> {
+    if let Some(name) = name {
+        ensure!(!name.is_empty(), ConditionalError::EmptyName {});
+    }
+    ensure_not_frozen(deps.storage, name)?;
+    validate_condition(deps, condition)?;
+    Ok(())
+}
+
+pub fn add_condition(
+    ctx: ExecuteContext,
+    condition: Condition,
+    name: Option<String>,
+) -> Result<Response, ContractError> {
+    ensure!(
+        !TIMELOCK.exists(ctx.deps.storage),
+        ConditionalError::TimelockActive {}
+    );
+    validate_condition_change(&ctx.deps.as_ref(), &condition, name.as_deref())?;
+    let author = original_sender(&ctx);
+    let version = save_condition(
+        ctx.deps.storage,
+        &ctx.env.block,
+        &author,
+        name.as_deref(),
+        &condition,
+    )?;
     Ok(Response::new()
         .add_attribute("method", "add_condition")
+        .add_attributes(name.map(|name| ("name", name)))
+        .add_attribute("version", version.to_string())
         .add_attribute("condition", format!("{condition:?}")))
 }
 
+pub fn set_timelock(ctx: ExecuteContext, delay: u64) -> Result<Response, ContractError> {
+    ensure!(
+        ADOContract::default().is_contract_owner(ctx.deps.storage, ctx.info.sender.as_str())?,
+        ContractError::Unauthorized {}
+    );
+    // A shorter timelock would let pending changes through earlier than announced
+    if let Some(current) = TIMELOCK.may_load(ctx.deps.storage)? {
+        ensure!(delay >= current, ConditionalError::TimelockDecrease {});
+    }
+    TIMELOCK.save(ctx.deps.storage, &delay)?;
+    Ok(Response::new()
+        .add_attribute("method", "set_timelock")
+        .add_attribute("delay", delay.to_string()))
+}
+
+pub fn propose_condition(
+    ctx: ExecuteContext,
+    condition: Condition,
+    name: Option<String>,
+) -> Result<Response, ContractError> {
+    // Proposals are the way to add conditions while a timelock is set, so they need the
+    // same permission
+    ensure!(
+        is_context_permissioned(
+            ctx.deps.storage,
+            &ctx.info,
+            &ctx.env,
+            &ctx.amp_ctx,
+            "AddCondition"
+        )?,
+        ContractError::Unauthorized {}
+    );
+    validate_condition_change(&ctx.deps.as_ref(), &condition, name.as_deref())?;
+    let key = name.as_deref().unwrap_or_default();
+    let proposer = original_sender(&ctx);
+    // A new proposal replaces the pending one and waits the full delay again, which only
+    // those who could cancel the pending one may do
+    if let Some(pending) = PENDING_CONDITIONS.may_load(ctx.deps.storage, key)? {
+        ensure!(
+            pending.proposer == proposer
+                || ADOContract::default()
+                    .is_contract_owner(ctx.deps.storage, ctx.info.sender.as_str())?,
+            ContractError::Unauthorized {}
+        );
+    }
+    let delay = TIMELOCK.may_load(ctx.deps.storage)?.unwrap_or_default();
+    let effective = ctx.env.block.time.plus_seconds(delay);
+    PENDING_CONDITIONS.save(
+        ctx.deps.storage,
+        key,
+        &PendingCondition {
+            condition: condition.clone(),
+            proposer,
+            effective,
+        },
+    )?;
+    Ok(Response::new()
+        .add_attribute("method", "propose_condition")
+        .add_attributes(name.map(|name| ("name", name)))
+        .add_attribute("effective", effective.to_string())
+        .add_attribute("condition", format!("{condition:?}")))
+}
+
+pub fn apply_condition(
+    ctx: ExecuteContext,
+    name: Option<String>,
+) -> Result<Response, ContractError> {
+    let key = name.as_deref().unwrap_or_default();
+    let pending = PENDING_CONDITIONS
+        .may_load(ctx.deps.storage, key)?
+        .ok_or(ConditionalError::NoPendingCondition {})?;
+    ensure!(
+        ctx.env.block.time >= pending.effective,
+        ConditionalError::ProposalNotReady {
+            effective: pending.effective
+        }
+    );
+    // The condition may have been frozen and variables changed since the proposal
+    ensure_not_frozen(ctx.deps.storage, name.as_deref())?;
+    check_condition_dependencies(ctx.deps.storage, &pending.condition)?;
+    PENDING_CONDITIONS.remove(ctx.deps.storage, key);
+    let version = save_condition(
+        ctx.deps.storage,
+        &ctx.env.block,
+        &pending.proposer,
+        name.as_deref(),
+        &pending.condition,
+    )?;
+    Ok(Response::new()
+        .add_attribute("method", "apply_condition")
+        .add_attributes(name.map(|name| ("name", name)))
+        .add_attribute("version", version.to_string()))
+}
+
+pub fn cancel_proposal(
+    ctx: ExecuteContext,
+    name: Option<String>,
+) -> Result<Response, ContractError> {
+    let key = name.as_deref().unwrap_or_default();
+    let pending = PENDING_CONDITIONS
+        .may_load(ctx.deps.storage, key)?
+        .ok_or(ConditionalError::NoPendingCondition {})?;
+    ensure!(
+        pending.proposer == original_sender(&ctx)
+            || ADOContract::default()
+                .is_contract_owner(ctx.deps.storage, ctx.info.sender.as_str())?,
+        ContractError::Unauthorized {}
+    );
+    PENDING_CONDITIONS.remove(ctx.deps.storage, key);
+    Ok(Response::new()
+        .add_attribute("method", "cancel_proposal")
+        .add_attributes(name.map(|name| ("name", name))))
+}
+
 pub fn add_variable(
     ctx: ExecuteContext,
     variable: &Variable,
     name: &str,
 ) -> Result<Response, ContractError> {
+    validate_variable(&ctx.deps.as_ref(), name, variable)?;
+    ensure_not_frozen(ctx.deps.storage, Some(name))?;
+    // Defining or changing a name changes every condition that reads it, which the
+    // timelock delays
+    if TIMELOCK.exists(ctx.deps.storage) {
+        ensure!(
+            !stored_condition_names(ctx.deps.storage)?.contains(name),
+            ConditionalError::TimelockedVariable {
+                name: name.to_string()
+            }
+        );
+    }
+    save_variable_dependencies(ctx.deps.storage, name, variable)?;
     VARIABLES.save(ctx.deps.storage, name, variable)?;
     Ok(Response::new()
         .add_attribute("method", "add_variable")
         .add_attribute("name", name)
         .add_attribute("condition", format!("{variable:?}")))
 }
+
+fn ensure_not_frozen(storage: &dyn Storage, name: Option<&str>) -> Result<(), ContractError> {
+    ensure!(
+        !is_frozen(storage, name)?,
+        ConditionalError::Frozen {
+            name: name.unwrap_or("Default condition").to_string()
+        }
+    );
+    Ok(())
+}
+
+pub fn freeze(ctx: ExecuteContext, name: Option<String>) -> Result<Response, ContractError> {
+    ensure!(
+        ADOContract::default().is_contract_owner(ctx.deps.storage, ctx.info.sender.as_str())?,
+        ContractError::Unauthorized {}
+    );
+    if let Some(name) = &name {
+        ensure!(!name.is_empty(), ConditionalError::EmptyName {});
+    }
+    let key = name.as_deref().unwrap_or_default();
+    // What is frozen could otherwise still change through the names it reads, including
+    // literals that a variable of the same name would replace
+    let mut names = BTreeSet::from([key.to_string()]);
+    let condition = match &name {
+        Some(name) => CONDITIONS.may_load(ctx.deps.storage, name)?,
+        None => CONDITION.may_load(ctx.deps.storage)?,
+    };
+    if let Some(condition) = condition {
+        names.extend(condition_names(ctx.deps.storage, &condition)?);
+    }
+    if VARIABLES.has(ctx.deps.storage, key) {
+        names.extend(transitive_dependencies(ctx.deps.storage, key)?);
+    }
+    for name in &names {
+        FROZEN.save(ctx.deps.storage, name, &Empty {})?;
+    }
+    Ok(Response::new()
+        .add_attribute("method", "freeze")
+        .add_attributes(name.map(|name| ("name", name)))
+        .add_attribute("frozen", names.into_iter().collect::<Vec<_>>().join(",")))
+}
+
+pub fn freeze_all(ctx: ExecuteContext) -> Result<Response, ContractError> {
+    ensure!(
+        ADOContract::default().is_contract_owner(ctx.deps.storage, ctx.info.sender.as_str())?,
+        ContractError::Unauthorized {}
+    );
+    ALL_FROZEN.save(ctx.deps.storage, &true)?;
+    Ok(Response::new().add_attribute("method", "freeze_all"))
+}
+
+pub fn validate_limits(limits: &EvaluationLimits) -> Result<(), ContractError> {
+    ensure!(
+        limits.max_depth > 0
+            && limits.max_external_queries > 0
+            && limits.max_expression_tokens > 0
+            && limits.max_steps > 0,
+        ConditionalError::InvalidLimits {}
+    );
+    Ok(())
+}
+
+pub fn set_limits(
+    ctx: ExecuteContext,
+    limits: EvaluationLimits,
+) -> Result<Response, ContractError> {
+    ensure!(
+        ADOContract::default().is_contract_owner(ctx.deps.storage, ctx.info.sender.as_str())?,
+        ContractError::Unauthorized {}
+    );
+    validate_limits(&limits)?;
+    LIMITS.save(ctx.deps.storage, &limits)?;
+    Ok(Response::new().add_attribute("method", "set_limits"))
+}
+
+pub fn set_proxy(
+    ctx: ExecuteContext,
+    target: String,
+    allowed_msgs: Option<Vec<String>>,
+) -> Result<Response, ContractError> {
+    // The target receives every forwarded message and its funds
+    ensure!(
+        ADOContract::default().is_contract_owner(ctx.deps.storage, ctx.info.sender.as_str())?,
+        ContractError::Unauthorized {}
+    );
+    let target = ctx.deps.api.addr_validate(&target)?;
+    PROXY.save(
+        ctx.deps.storage,
+        &ProxyConfig {
+            target: target.clone(),
+            allowed_msgs,
+        },
+    )?;
+    Ok(Response::new()
+        .add_attribute("method", "set_proxy")
+        .add_attribute("target", target))
+}
+
+pub fn forward(
+    ctx: ExecuteContext,
+    msg: Binary,
+    funds: Vec<Coin>,
+) -> Result<Response, ContractError> {
+    let proxy = PROXY
+        .may_load(ctx.deps.storage)?
+        .ok_or(ConditionalError::ProxyNotConfigured {})?;
+    if let Some(allowed_msgs) = &proxy.allowed_msgs {
+        let msg_type = forwarded_msg_type(&msg)?;
+        ensure!(
+            allowed_msgs.contains(&msg_type),
+            ConditionalError::ForwardNotAllowed { msg_type }
+        );
+    }
+    // The contract balance also holds escrows and trigger balances, so exactly the sent
+    // funds are forwarded and nothing is left behind
+    ensure!(
+        sum_coins(&funds)? == sum_coins(&ctx.info.funds)?,
+        ConditionalError::FundsMismatch {}
+    );
+
+    // The forwarded message is what the condition guards, so it becomes the ctx msg
+    let condition_ctx = create_verified_condition_ctx(&ctx, msg.clone());
+    let condition = CONDITION.load(ctx.deps.storage)?;
+    ensure!(
+        evaluate_condition(&ctx.deps.as_ref(), &condition_ctx, condition)?,
+        ConditionalError::ConditionNotMet {}
+    );
+
+    Ok(Response::new()
+        .add_message(WasmMsg::Execute {
+            contract_addr: proxy.target.to_string(),
+            msg,
+            funds,
+        })
+        .add_attribute("method", "forward")
+        .add_attribute("target", proxy.target))
+}
+
+fn forwarded_msg_type(msg: &Binary) -> Result<String, ContractError> {
+    match from_slice::<Value>(msg)? {
+        Value::Map(map) if map.len() == 1 => match map.into_keys().next() {
+            Some(Value::String(msg_type)) => Ok(msg_type),
+            _ => Err(ConditionalError::InvalidForwardMsg {}.into()),
+        },
+        _ => Err(ConditionalError::InvalidForwardMsg {}.into()),
+    }
+}
+
+pub fn deposit(
+    ctx: ExecuteContext,
+    recipient: String,
+    condition_name: String,
+    refund_condition_name: Option<String>,
+    expiry: Expiration,
+) -> Result<Response, ContractError> {
+    ensure!(!ctx.info.funds.is_empty(), ConditionalError::NoFunds {});
+    ensure!(
+        !expiry.is_expired(&ctx.env.block),
+        ConditionalError::EscrowExpired {}
+    );
+    // Unknown conditions could never release the funds, so they are rejected upfront
+    for name in std::iter::once(&condition_name).chain(refund_condition_name.iter()) {
+        ensure!(
+            CONDITIONS.has(ctx.deps.storage, name),
+            ConditionalError::ConditionNotFound { name: name.clone() }
+        );
+    }
+
+    let escrow = Escrow {
+        depositor: ctx.info.sender.clone(),
+        recipient: ctx.deps.api.addr_validate(&recipient)?,
+        funds: ctx.info.funds.clone(),
+        condition_name,
+        refund_condition_name,
+        expiry,
+    };
+    let id = NEXT_ESCROW_ID
+        .may_load(ctx.deps.storage)?
+        .unwrap_or_default();
+    NEXT_ESCROW_ID.save(ctx.deps.storage, &(id + 1))?;
+    ESCROWS.save(ctx.deps.storage, id, &escrow)?;
+
+    Ok(Response::new()
+        .add_attribute("method", "deposit")
+        .add_attribute("id", id.to_string())
+        .add_attribute("depositor", escrow.depositor)
+        .add_attribute("recipient", escrow.recipient))
+}
+
+pub fn release(ctx: ExecuteContext, id: u64) -> Result<Response, ContractError> {
+    let escrow = ESCROWS.load(ctx.deps.storage, id)?;
+    ensure!(
+        !escrow.expiry.is_expired(&ctx.env.block),
+        ConditionalError::EscrowExpired {}
+    );
+
+    let condition = CONDITIONS.load(ctx.deps.storage, &escrow.condition_name)?;
+    let condition_ctx =
+        create_verified_condition_ctx(&ctx, to_binary(&ExecuteMsg::Release { id })?);
+    ensure!(
+        evaluate_condition(&ctx.deps.as_ref(), &condition_ctx, condition)?,
+        ConditionalError::ConditionNotMet {}
+    );
+    ESCROWS.remove(ctx.deps.storage, id);
+
+    Ok(Response::new()
+        .add_message(BankMsg::Send {
+            to_address: escrow.recipient.to_string(),
+            amount: escrow.funds,
+        })
+        .add_attribute("method", "release")
+        .add_attribute("id", id.to_string())
+        .add_attribute("recipient", escrow.recipient))
+}
+
+pub fn refund(ctx: ExecuteContext, id: u64) -> Result<Response, ContractError> {
+    let escrow = ESCROWS.load(ctx.deps.storage, id)?;
+    let refundable = escrow.expiry.is_expired(&ctx.env.block)
+        || match &escrow.refund_condition_name {
+            Some(name) => {
+                let condition = CONDITIONS.load(ctx.deps.storage, name)?;
+                let condition_ctx =
+                    create_verified_condition_ctx(&ctx, to_binary(&ExecuteMsg::Refund { id })?);
+                evaluate_condition(&ctx.deps.as_ref(), &condition_ctx, condition)?
+            }
+            None => false,
+        };
+    ensure!(refundable, ConditionalError::RefundNotAllowed {});
+    ESCROWS.remove(ctx.deps.storage, id);
+
+    Ok(Response::new()
+        .add_message(BankMsg::Send {
+            to_address: escrow.depositor.to_string(),
+            amount: escrow.funds,
+        })
+        .add_attribute("method", "refund")
+        .add_attribute("id", id.to_string())
+        .add_attribute("depositor", escrow.depositor))
+}
+
+pub fn add_trigger(ctx: ExecuteContext, trigger: Trigger) -> Result<Response, ContractError> {
+    // Trigger messages are dispatched with the contract's own authority
+    ensure!(
+        ADOContract::default().is_contract_owner(ctx.deps.storage, ctx.info.sender.as_str())?,
+        ContractError::Unauthorized {}
+    );
+    ensure!(
+        CONDITIONS.has(ctx.deps.storage, &trigger.condition_name),
+        ConditionalError::ConditionNotFound {
+            name: trigger.condition_name
+        }
+    );
+
+    let id = NEXT_TRIGGER_ID
+        .may_load(ctx.deps.storage)?
+        .unwrap_or_default();
+    NEXT_TRIGGER_ID.save(ctx.deps.storage, &(id + 1))?;
+    TRIGGERS.save(
+        ctx.deps.storage,
+        id,
+        &TriggerInfo {
+            creator: ctx.info.sender.clone(),
+            trigger,
+            balance: ctx.info.funds.clone(),
+        },
+    )?;
+
+    Ok(Response::new()
+        .add_attribute("method", "add_trigger")
+        .add_attribute("trigger_id", id.to_string()))
+}
+
+pub fn remove_trigger(ctx: ExecuteContext, trigger_id: u64) -> Result<Response, ContractError> {
+    let info = TRIGGERS.load(ctx.deps.storage, trigger_id)?;
+    ensure!(
+        info.creator == ctx.info.sender,
+        ContractError::Unauthorized {}
+    );
+    TRIGGERS.remove(ctx.deps.storage, trigger_id);
+
+    Ok(Response::new()
+        .add_messages(bank_send(&info.creator, info.balance))
+        .add_attribute("method", "remove_trigger")
+        .add_attribute("trigger_id", trigger_id.to_string()))
+}
+
+pub fn fire(ctx: ExecuteContext, trigger_id: u64) -> Result<Response, ContractError> {
+    let mut info = TRIGGERS.load(ctx.deps.storage, trigger_id)?;
+    let condition = CONDITIONS.load(ctx.deps.storage, &info.trigger.condition_name)?;
+    let condition_ctx =
+        create_verified_condition_ctx(&ctx, to_binary(&ExecuteMsg::Fire { trigger_id })?);
+    ensure!(
+        evaluate_condition(&ctx.deps.as_ref(), &condition_ctx, condition)?,
+        ConditionalError::ConditionNotMet {}
+    );
+    pay_for_fire(&mut info)?;
+
+    let mut resp = Response::new()
+        .add_messages(info.trigger.msgs.clone())
+        .add_messages(bank_send(&ctx.info.sender, info.trigger.reward.clone()));
+    if info.trigger.one_shot {
+        TRIGGERS.remove(ctx.deps.storage, trigger_id);
+        resp = resp.add_messages(bank_send(&info.creator, info.balance));
+    } else {
+        TRIGGERS.save(ctx.deps.storage, trigger_id, &info)?;
+    }
+
+    Ok(resp
+        .add_attribute("method", "fire")
+        .add_attribute("trigger_id", trigger_id.to_string())
+        .add_attribute("keeper", ctx.info.sender))
+}
+
+/// Takes what firing costs from the trigger balance. The contract balance also holds
+/// escrows and other triggers, so whatever the msgs send is paid from it like the reward.
+pub fn pay_for_fire(info: &mut TriggerInfo) -> Result<(), ContractError> {
+    for msg in &info.trigger.msgs {
+        deduct_coins(&mut info.balance, &msg_funds(msg))?;
+    }
+    deduct_coins(&mut info.balance, &info.trigger.reward)
+}
+
+/// Coins a message takes from the contract balance
+fn msg_funds(msg: &CosmosMsg) -> Vec<Coin> {
+    match msg {
+        CosmosMsg::Bank(BankMsg::Send { amount, .. } | BankMsg::Burn { amount }) => amount.clone(),
+        CosmosMsg::Wasm(WasmMsg::Execute { funds, .. } | WasmMsg::Instantiate { funds, .. }) => {
+            funds.clone()
+        }
+        CosmosMsg::Staking(StakingMsg::Delegate { amount, .. }) => vec![amount.clone()],
+        _ => vec![],
+    }
+}
+
+/// Total amount per denom, coins of the same denom are added up and zero coins dropped
+fn sum_coins(coins: &[Coin]) -> Result<BTreeMap<String, Uint128>, ContractError> {
+    let mut total = BTreeMap::<String, Uint128>::new();
+    for coin in coins.iter().filter(|coin| !coin.amount.is_zero()) {
+        let amount = total.entry(coin.denom.clone()).or_default();
+        *amount = amount.checked_add(coin.amount).map_err(StdError::from)?;
+    }
+    Ok(total)
+}
+
+/// Deducts `amount` from `balance`, dropping coins that reach zero
+fn deduct_coins(balance: &mut Vec<Coin>, amount: &[Coin]) -> Result<(), ContractError> {
+    for coin in amount {
+        let index = balance
+            .iter()
+            .position(|c| c.denom == coin.denom && c.amount >= coin.amount)
+            .ok_or(ConditionalError::InsufficientTriggerBalance {})?;
+        balance[index].amount -= coin.amount;
+        if balance[index].amount.is_zero() {
+            balance.remove(index);
+        }
+    }
+    Ok(())
+}
+
+/// Bank sends without coins are rejected by the chain, so they are skipped
+fn bank_send(to_address: &Addr, amount: Vec<Coin>) -> Option<BankMsg> {
+    (!amount.is_empty()).then(|| BankMsg::Send {
+        to_address: to_address.to_string(),
+        amount,
+    })
+}
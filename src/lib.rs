@@ -1,12 +1,17 @@
 pub mod contract;
+mod dependencies;
 mod error;
+mod evaluation;
 mod execute;
 pub mod helpers;
+mod migrations;
 pub mod msg;
 mod packages;
 mod query;
 pub mod state;
+mod template;
 mod tests;
 mod types;
+mod validation;
 
 pub use crate::error::ContractError;
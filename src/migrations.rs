@@ -0,0 +1,134 @@
+use cosmwasm_std::{Addr, BlockInfo, Order, StdError, StdResult, Storage};
+use cw_storage_plus::{Item, Map};
+use semver::Version;
+use serde_cw_value::Value;
+
+use crate::{
+    state::{CONDITION, CONDITIONS, CONDITION_HISTORY, VARIABLES},
+    types::ConditionRecord,
+};
+
+// Same namespaces as in state, read as plain json so entries in an older format still load
+const RAW_CONDITION: Item<Value> = Item::new("condition");
+const RAW_CONDITIONS: Map<&str, Value> = Map::new("conditions");
+const RAW_VARIABLES: Map<&str, Value> = Map::new("variables");
+
+/// Rewrites a stored condition or variable into the format of the next version
+type Rewrite = fn(Value) -> StdResult<Value>;
+
+/// A change to how conditions or variables are serialized. Entries written by a version
+/// older than `version` are rewritten when migrating to it or any later version.
+struct Migration {
+    version: &'static str,
+    condition: Rewrite,
+    variable: Rewrite,
+}
+
+/// Ordered by version
+const MIGRATIONS: &[Migration] = &[Migration {
+    version: "0.2.0",
+    condition: add_require_verified,
+    variable: unchanged,
+}];
+
+pub fn parse_version(version: &str) -> StdResult<Version> {
+    Version::parse(version).map_err(|err| StdError::generic_err(err.to_string()))
+}
+
+/// Runs every migration newer than `from` over the stored conditions and variables
+pub fn migrate_state(storage: &mut dyn Storage, from: &Version) -> StdResult<()> {
+    for migration in MIGRATIONS {
+        if *from >= parse_version(migration.version)? {
+            continue;
+        }
+        if let Some(condition) = RAW_CONDITION.may_load(storage)? {
+            RAW_CONDITION.save(storage, &(migration.condition)(condition)?)?;
+        }
+        let conditions = RAW_CONDITIONS
+            .range(storage, None, None, Order::Ascending)
+            .collect::<StdResult<Vec<_>>>()?;
+        for (name, condition) in conditions {
+            RAW_CONDITIONS.save(storage, &name, &(migration.condition)(condition)?)?;
+        }
+        let variables = RAW_VARIABLES
+            .range(storage, None, None, Order::Ascending)
+            .collect::<StdResult<Vec<_>>>()?;
+        for (name, variable) in variables {
+            RAW_VARIABLES.save(storage, &name, &(migration.variable)(variable)?)?;
+        }
+    }
+
+    // Every entry has to load in the current format once all migrations ran
+    CONDITION.may_load(storage)?;
+    for item in CONDITIONS.range(storage, None, None, Order::Ascending) {
+        item?;
+    }
+    for item in VARIABLES.range(storage, None, None, Order::Ascending) {
+        item?;
+    }
+    Ok(())
+}
+
+/// Conditions stored before the history existed are recorded as their first version.
+/// Who wrote them is unknown, so `author` is recorded instead.
+pub fn record_condition_history(
+    storage: &mut dyn Storage,
+    block: &BlockInfo,
+    author: &Addr,
+) -> StdResult<()> {
+    let mut conditions = CONDITIONS
+        .range(storage, None, None, Order::Ascending)
+        .collect::<StdResult<Vec<_>>>()?;
+    // The default condition's history is stored under an empty name
+    if let Some(condition) = CONDITION.may_load(storage)? {
+        conditions.push((String::new(), condition));
+    }
+    for (name, condition) in conditions {
+        let recorded = CONDITION_HISTORY
+            .prefix(&name)
+            .keys(storage, None, None, Order::Ascending)
+            .next()
+            .is_some();
+        if !recorded {
+            CONDITION_HISTORY.save(
+                storage,
+                (&name, 1),
+                &ConditionRecord {
+                    condition,
+                    author: author.clone(),
+                    height: block.height,
+                    time: block.time,
+                },
+            )?;
+        }
+    }
+    Ok(())
+}
+
+fn unchanged(value: Value) -> StdResult<Value> {
+    Ok(value)
+}
+
+/// Conditions written before 0.2.0 have no `require_verified`, nested conditions included
+fn add_require_verified(condition: Value) -> StdResult<Value> {
+    let mut condition = match condition {
+        Value::Map(condition) => condition,
+        _ => {
+            return Err(StdError::generic_err(
+                "Stored condition is not a json object",
+            ))
+        }
+    };
+    condition
+        .entry(Value::String("require_verified".to_string()))
+        .or_insert(Value::Option(None));
+    for wing in ["left", "right"] {
+        if let Some(Value::Map(wing)) = condition.get_mut(&Value::String(wing.to_string())) {
+            let key = Value::String("condition".to_string());
+            if let Some(nested) = wing.remove(&key) {
+                wing.insert(key, add_require_verified(nested)?);
+            }
+        }
+    }
+    Ok(Value::Map(condition))
+}
@@ -1,38 +1,264 @@
 use andromeda_std::{andr_exec, andr_instantiate, andr_query};
 use cosmwasm_schema::{cw_serde, QueryResponses};
+use cosmwasm_std::{Binary, Coin};
+use cw_utils::Expiration;
 
-use crate::types::{Condition, InwardExecuteCtx, Variable};
+use crate::types::{
+    BatchItem, Condition, ConditionRecord, Escrow, EvaluationLimits, InwardExecuteCtx, Overrides,
+    PendingCondition, ProxyConfig, Trigger, TriggerInfo, Variable,
+};
 
 #[andr_instantiate]
 #[cw_serde]
-pub struct InstantiateMsg {}
+pub struct InstantiateMsg {
+    #[serde(default)]
+    pub variables: Vec<(String, Variable)>,
+    /// Default condition
+    pub condition: Option<Condition>,
+    /// Named conditions
+    #[serde(default)]
+    pub conditions: Vec<(String, Condition)>,
+    pub limits: Option<EvaluationLimits>,
+    /// Seconds condition changes have to wait, conditions can be changed at once when not set
+    pub timelock: Option<u64>,
+}
+
+#[cw_serde]
+pub struct MigrateMsg {}
 
 #[andr_exec]
 #[cw_serde]
 pub enum ExecuteMsg {
-    AddVariable { variable: Variable, name: String },
-    AddCondition { condition: Condition },
+    /// Variables read by a stored condition cannot be changed while a timelock is set
+    AddVariable {
+        variable: Variable,
+        name: String,
+    },
+    /// Sets a named condition, or the default condition when no name is given
+    AddCondition {
+        condition: Condition,
+        name: Option<String>,
+    },
+    SetProxy {
+        target: String,
+        allowed_msgs: Option<Vec<String>>,
+    },
+    /// Relays `msg` to the proxy target only if the stored condition holds for it
+    Forward {
+        msg: Binary,
+        funds: Vec<Coin>,
+    },
+    /// Holds the sent funds until `condition_name` holds for the release
+    Deposit {
+        recipient: String,
+        condition_name: String,
+        refund_condition_name: Option<String>,
+        expiry: Expiration,
+    },
+    Release {
+        id: u64,
+    },
+    /// Returns the funds to the depositor after expiry or when the refund condition holds
+    Refund {
+        id: u64,
+    },
+    /// Registers msgs to dispatch once the named condition holds, funds sent along pay
+    /// the reward of whoever fires it
+    AddTrigger {
+        trigger: Trigger,
+    },
+    /// Removes a trigger and returns its remaining balance to the creator
+    RemoveTrigger {
+        trigger_id: u64,
+    },
+    /// Dispatches the msgs of a trigger whose condition holds and pays its reward to the
+    /// sender. There is no cooldown: a trigger that is not one shot can be fired again,
+    /// even within the same block, for as long as its condition holds and its balance
+    /// pays for it. Conditions of such triggers have to limit how often they hold.
+    Fire {
+        trigger_id: u64,
+    },
+    SetLimits {
+        limits: EvaluationLimits,
+    },
+    /// Sets the timelock of condition changes and the variables they read, it can only
+    /// ever be increased
+    SetTimelock {
+        delay: u64,
+    },
+    /// Proposes a change to a named condition, or the default condition when no name is
+    /// given, that can be applied once the timelock passed. Needs the permission of
+    /// `AddCondition`, and only the proposer or the owner can replace a pending proposal.
+    ProposeCondition {
+        condition: Condition,
+        name: Option<String>,
+    },
+    ApplyCondition {
+        name: Option<String>,
+    },
+    /// Withdraws a proposal, only the proposer or the owner can cancel it
+    CancelProposal {
+        name: Option<String>,
+    },
+    /// Permanently prevents the condition and variable named `name`, or the default
+    /// condition when no name is given, from being changed by anyone including the owner.
+    /// The variables they read are frozen with them.
+    Freeze {
+        name: Option<String>,
+    },
+    /// Freezes every condition and variable, the default condition and ones added later
+    /// included
+    FreezeAll {},
 }
 
 #[andr_query]
 #[cw_serde]
 #[derive(QueryResponses)]
 pub enum QueryMsg {
+    /// Evaluates a named condition, or the default condition when no name is given
     #[returns(bool)]
-    Evaluate { ctx: Option<InwardExecuteCtx> },
+    Evaluate {
+        name: Option<String>,
+        ctx: Option<InwardExecuteCtx>,
+        #[serde(default)]
+        #[schemars(schema_with = "crate::types::overrides_schema")]
+        overrides: Overrides,
+    },
     #[returns(bool)]
     EvaluateCondition {
         condition: Condition,
         ctx: Option<InwardExecuteCtx>,
+        #[serde(default)]
+        #[schemars(schema_with = "crate::types::overrides_schema")]
+        overrides: Overrides,
     },
     #[returns(String)]
     EvaluateVariable {
         name: String,
         ctx: Option<InwardExecuteCtx>,
+        #[serde(default)]
+        #[schemars(schema_with = "crate::types::overrides_schema")]
+        overrides: Overrides,
     },
     #[returns(String)]
     EvaluateCustomVariable {
         variable: Variable,
         ctx: Option<InwardExecuteCtx>,
     },
+    #[returns(Option<ProxyConfig>)]
+    Proxy {},
+    #[returns(Escrow)]
+    Escrow { id: u64 },
+    #[returns(TriggerInfo)]
+    Trigger { trigger_id: u64 },
+    /// Ids of triggers that can be fired, among at most `limit` triggers after
+    /// `start_after`. Triggers that cannot pay for their msgs and reward are skipped.
+    #[returns(FireableResponse)]
+    ListFireable {
+        start_after: Option<u64>,
+        limit: Option<u32>,
+    },
+    /// Variables the named variable depends on, directly or through other variables
+    #[returns(Vec<String>)]
+    Dependencies { name: String },
+    /// Variables a stored condition depends on, the default condition when no name is given
+    #[returns(Vec<String>)]
+    ConditionDependencies { name: Option<String> },
+    /// Evaluates a stored condition like `Evaluate` and reports how the result was reached
+    #[returns(ExplainResponse)]
+    Explain {
+        name: Option<String>,
+        ctx: Option<InwardExecuteCtx>,
+    },
+    /// Evaluates every item against the same ctx, sharing resolved variables and query
+    /// results between items. Limits apply to each item on its own.
+    #[returns(Vec<BatchItemResult>)]
+    EvaluateBatch {
+        items: Vec<BatchItem>,
+        ctx: Option<InwardExecuteCtx>,
+    },
+    /// Evaluates a past version of a stored condition, the default condition when no name
+    /// is given. Variables are read as they are now.
+    #[returns(bool)]
+    EvaluateAt {
+        name: Option<String>,
+        version: u32,
+        ctx: Option<InwardExecuteCtx>,
+    },
+    /// Versions of a stored condition with who set them when, oldest first
+    #[returns(Vec<(u32, ConditionRecord)>)]
+    ConditionHistory {
+        name: Option<String>,
+        start_after: Option<u32>,
+        limit: Option<u32>,
+    },
+    /// The active and the pending version of a stored condition, the default condition
+    /// when no name is given
+    #[returns(ConditionStatus)]
+    ConditionStatus { name: Option<String> },
+    #[returns(Option<u64>)]
+    Timelock {},
+    /// Whether a condition or variable is frozen, the default condition when no name is
+    /// given
+    #[returns(bool)]
+    IsFrozen { name: Option<String> },
+    #[returns(FrozenResponse)]
+    Frozen {
+        start_after: Option<String>,
+        limit: Option<u32>,
+    },
+    #[returns(EvaluationLimits)]
+    Limits {},
+    /// Checks a condition the way `AddCondition` would use it without evaluating it
+    #[returns(Vec<Diagnostic>)]
+    ValidateCondition { condition: Condition },
+    #[returns(Vec<Diagnostic>)]
+    ValidateVariable { variable: Variable },
+}
+
+#[cw_serde]
+pub struct ExplainResponse {
+    pub result: bool,
+    /// Named variables with their value, in the order they were first resolved
+    pub variables: Vec<(String, Option<String>)>,
+    pub external_queries: u64,
+    /// Variables and external queries answered without resolving them again
+    pub cache_hits: u64,
+}
+
+/// Result of a batch item, a failing item does not fail the others
+#[cw_serde]
+pub enum BatchItemResult {
+    Condition(bool),
+    Variable(Option<String>),
+    Error(String),
+}
+
+#[cw_serde]
+pub struct ConditionStatus {
+    pub active: Option<Condition>,
+    pub pending: Option<PendingCondition>,
+}
+
+#[cw_serde]
+pub struct FireableResponse {
+    pub trigger_ids: Vec<u64>,
+    /// Last trigger scanned, to pass as `start_after` for the next page. `None` once no
+    /// trigger is left to scan.
+    pub last_scanned: Option<u64>,
+}
+
+#[cw_serde]
+pub struct FrozenResponse {
+    /// True once everything is frozen
+    pub all: bool,
+    /// Names frozen one by one, an empty name for the default condition
+    pub names: Vec<String>,
+}
+
+#[cw_serde]
+pub struct Diagnostic {
+    /// Dotted path of the field with the problem, empty for the condition or variable itself
+    pub path: String,
+    pub message: String,
 }
@@ -4,6 +4,11 @@ use cosmwasm_std::Int128;
 
 pub type Tokens = Vec<String>;
 
+/// True for the tokens that are not operands
+pub fn is_operator(token: &str) -> bool {
+    matches!(token, "+" | "-" | "*" | "/" | "(" | ")")
+}
+
 fn precedence(op: &str) -> u32 {
     match op {
         "+" | "-" => 1,
@@ -14,22 +19,44 @@ fn precedence(op: &str) -> u32 {
 
 fn apply_op(a: Int128, b: Int128, op: &str) -> Result<Int128, &'static str> {
     match op {
-        "+" => Ok(a + b),
-        "-" => Ok(a - b),
-        "*" => Ok(a * b),
+        "+" => a.checked_add(b).map_err(|_| "Overflow"),
+        "-" => a.checked_sub(b).map_err(|_| "Overflow"),
+        "*" => a.checked_mul(b).map_err(|_| "Overflow"),
+        "/" => a.checked_div(b).map_err(|_| "Division by zero"),
         _ => Err("Invalid operator"), // Handle invalid operator
     }
 }
 
+/// Checks that operands and operators alternate and parentheses are balanced, without
+/// resolving any operand
+pub fn check_syntax(tokens: &Tokens) -> Result<(), &'static str> {
+    let mut expect_operand = true;
+    let mut open = 0u32;
+    for token in tokens {
+        match token.as_str() {
+            "(" if expect_operand => open += 1,
+            ")" if !expect_operand => {
+                open = open.checked_sub(1).ok_or("Unbalanced parentheses")?;
+            }
+            "+" | "-" | "*" | "/" if !expect_operand => expect_operand = true,
+            token if !is_operator(token) && expect_operand => expect_operand = false,
+            _ => return Err("Invalid expression"),
+        }
+    }
+    if open > 0 {
+        return Err("Unbalanced parentheses");
+    }
+    if expect_operand {
+        return Err("Invalid expression");
+    }
+    Ok(())
+}
+
 pub fn evaluate(tokens: Tokens, variables: &HashMap<&str, Int128>) -> Result<Int128, &'static str> {
     let mut values = VecDeque::new();
     let mut ops = VecDeque::new();
 
-    println!("TOKENS::{tokens:?}");
-
     for token in tokens {
-        println!("OPS::{ops:?}");
-        println!("VALUE::{values:?}");
         if let Ok(constant) = token.parse::<Int128>() {
             values.push_back(constant);
         } else if let Some(&value) = variables.get(&token.as_str()) {
@@ -2,7 +2,7 @@
 mod tests {
     use cosmwasm_std::Int128;
 
-    use crate::packages::eval::eval::{evaluate, Tokens};
+    use crate::packages::eval::eval::{check_syntax, evaluate, Tokens};
     use std::collections::HashMap;
 
     fn tokenize(expr: &str) -> Tokens {
@@ -31,11 +31,36 @@ mod tests {
         assert_eq!(evaluate(tokenize("-5 - 5"), &variables), Ok((-10).into()));
         assert_eq!(evaluate(tokenize("10 - -2"), &variables), Ok(12.into()));
 
-        // // Test expressions with division by zero
-        // assert_eq!(
-        //     evaluate(tokenize("5 / 0"), &variables),
-        //     Err("Division by zero")
-        // );
+        // Test division, which rounds towards zero
+        assert_eq!(evaluate(tokenize("10 / 3"), &variables), Ok(3.into()));
+        assert_eq!(
+            evaluate(tokenize("2000000 * 3 / 1000000"), &variables),
+            Ok(6.into())
+        );
+
+        // Test expressions that overflow, as 18 decimal amounts can
+        assert_eq!(
+            evaluate(
+                tokenize("1000000000000000000000000 * 1000000000000000000000 / 1000000"),
+                &variables
+            ),
+            Err("Overflow")
+        );
+        let max = Int128::MAX.to_string();
+        assert_eq!(
+            evaluate(tokenize(&format!("{max} + 1")), &variables),
+            Err("Overflow")
+        );
+        assert_eq!(
+            evaluate(tokenize(&format!("-{max} - 2")), &variables),
+            Err("Overflow")
+        );
+
+        // Test expressions with division by zero
+        assert_eq!(
+            evaluate(tokenize("5 / 0"), &variables),
+            Err("Division by zero")
+        );
 
         // Test expressions with invalid operators
         assert_eq!(
@@ -60,4 +85,25 @@ mod tests {
             Ok(49.into())
         );
     }
+
+    #[test]
+    fn test_check_syntax() {
+        assert_eq!(check_syntax(&tokenize("a")), Ok(()));
+        assert_eq!(check_syntax(&tokenize("-5 + 3")), Ok(()));
+        assert_eq!(check_syntax(&tokenize("( ( a + b ) * 7 )")), Ok(()));
+
+        assert_eq!(check_syntax(&tokenize("")), Err("Invalid expression"));
+        assert_eq!(check_syntax(&tokenize("5 +")), Err("Invalid expression"));
+        assert_eq!(check_syntax(&tokenize("5 5")), Err("Invalid expression"));
+        assert_eq!(check_syntax(&tokenize("* 5")), Err("Invalid expression"));
+        assert_eq!(check_syntax(&tokenize("( )")), Err("Invalid expression"));
+        assert_eq!(
+            check_syntax(&tokenize("( ( 5 + 2 ) * 3")),
+            Err("Unbalanced parentheses")
+        );
+        assert_eq!(
+            check_syntax(&tokenize("5 + 2 )")),
+            Err("Unbalanced parentheses")
+        );
+    }
 }
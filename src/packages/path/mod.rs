@@ -0,0 +1,3 @@
+pub mod path;
+
+mod test;
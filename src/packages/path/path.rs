@@ -0,0 +1,261 @@
+use cosmwasm_std::Int128;
+use serde_cw_value::Value;
+use serde_json_wasm::to_string;
+
+/// A step of a path such as `balances[?denom=='uatom'].amount`
+#[derive(Debug, Clone, PartialEq)]
+pub enum Segment {
+    /// Map key, or array index when the key is a number
+    Key(String),
+    Index(usize),
+    /// Every element of an array or value of a map
+    Wildcard,
+    /// Array elements matching the predicate
+    Filter(Filter),
+    /// `length()`, only valid as the last segment
+    Length,
+    /// `sum()`, only valid as the last segment
+    Sum,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Filter {
+    pub field: Vec<String>,
+    pub op: FilterOp,
+    pub value: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum FilterOp {
+    Eq,
+    Neq,
+    Lt,
+    Lte,
+    Gt,
+    Gte,
+}
+
+pub fn parse_path(path: &str) -> Result<Vec<Segment>, &'static str> {
+    let mut segments = vec![];
+    let mut rest = path;
+    while !rest.is_empty() {
+        if let Some(bracket) = rest.strip_prefix('[') {
+            let end = closing_bracket(bracket).ok_or("Unclosed bracket")?;
+            segments.push(parse_bracket(&bracket[..end])?);
+            rest = &bracket[end + 1..];
+            // A bracket is followed by another bracket, a dot or the end of the path
+            if let Some(next) = rest.strip_prefix('.') {
+                ensure_not_empty(next)?;
+                rest = next;
+            }
+            continue;
+        }
+        let end = rest.find(['.', '[']).unwrap_or(rest.len());
+        let key = &rest[..end];
+        segments.push(match key {
+            "" => return Err("Empty path segment"),
+            "*" => Segment::Wildcard,
+            "length()" => Segment::Length,
+            "sum()" => Segment::Sum,
+            _ => Segment::Key(key.to_string()),
+        });
+        rest = &rest[end..];
+        if let Some(next) = rest.strip_prefix('.') {
+            ensure_not_empty(next)?;
+            rest = next;
+        }
+    }
+    if segments.is_empty() {
+        return Err("Empty path");
+    }
+    let last = segments.len() - 1;
+    if segments[..last]
+        .iter()
+        .any(|segment| matches!(segment, Segment::Length | Segment::Sum))
+    {
+        return Err("Aggregates must be the last segment");
+    }
+    Ok(segments)
+}
+
+fn ensure_not_empty(rest: &str) -> Result<(), &'static str> {
+    if rest.is_empty() {
+        return Err("Empty path segment");
+    }
+    Ok(())
+}
+
+/// Position of the `]` closing a bracket, skipping quoted strings
+fn closing_bracket(bracket: &str) -> Option<usize> {
+    let mut quoted = false;
+    for (idx, c) in bracket.char_indices() {
+        match c {
+            '\'' => quoted = !quoted,
+            ']' if !quoted => return Some(idx),
+            _ => {}
+        }
+    }
+    None
+}
+
+fn parse_bracket(content: &str) -> Result<Segment, &'static str> {
+    let content = content.trim();
+    if content == "*" {
+        return Ok(Segment::Wildcard);
+    }
+    if let Some(predicate) = content.strip_prefix('?') {
+        return parse_filter(predicate).map(Segment::Filter);
+    }
+    if let Some(key) = unquote(content) {
+        return Ok(Segment::Key(key.to_string()));
+    }
+    content
+        .parse()
+        .map(Segment::Index)
+        .map_err(|_| "Invalid bracket")
+}
+
+fn parse_filter(predicate: &str) -> Result<Filter, &'static str> {
+    // Two character operators are matched first so `<=` is not read as `<`
+    let (idx, op, len) = [
+        ("==", FilterOp::Eq),
+        ("!=", FilterOp::Neq),
+        ("<=", FilterOp::Lte),
+        (">=", FilterOp::Gte),
+        ("<", FilterOp::Lt),
+        (">", FilterOp::Gt),
+    ]
+    .into_iter()
+    .find_map(|(token, op)| predicate.find(token).map(|idx| (idx, op, token.len())))
+    .ok_or("Invalid filter")?;
+    let field = predicate[..idx].trim();
+    let value = predicate[idx + len..].trim();
+    if field.is_empty() {
+        return Err("Invalid filter");
+    }
+    let value = match unquote(value) {
+        Some(value) => value.to_string(),
+        None if value.parse::<Int128>().is_ok() => value.to_string(),
+        None => return Err("Invalid filter value"),
+    };
+    Ok(Filter {
+        field: field.split('.').map(String::from).collect(),
+        op,
+        value,
+    })
+}
+
+fn unquote(value: &str) -> Option<&str> {
+    value.strip_prefix('\'')?.strip_suffix('\'')
+}
+
+/// Resolves the path against a value. Paths with a wildcard or filter select a list of
+/// values, other paths select at most one value.
+pub fn select(value: &Value, segments: &[Segment]) -> Result<Option<Value>, &'static str> {
+    let mut nodes = vec![value];
+    let mut definite = true;
+    for segment in segments {
+        nodes = match segment {
+            Segment::Key(key) => nodes.into_iter().filter_map(|n| child(n, key)).collect(),
+            Segment::Index(idx) => nodes
+                .into_iter()
+                .filter_map(|n| child(n, &idx.to_string()))
+                .collect(),
+            Segment::Wildcard => {
+                definite = false;
+                nodes.into_iter().flat_map(children).collect()
+            }
+            Segment::Filter(filter) => {
+                definite = false;
+                nodes
+                    .into_iter()
+                    .flat_map(children)
+                    .filter(|n| matches_filter(n, filter))
+                    .collect()
+            }
+            Segment::Length => {
+                let length = match (definite, nodes.first().copied()) {
+                    (false, _) => nodes.len(),
+                    (true, Some(Value::Seq(values))) => values.len(),
+                    (true, Some(Value::Map(map))) => map.len(),
+                    (true, Some(Value::String(value))) => value.chars().count(),
+                    _ => return Ok(None),
+                };
+                return Ok(Some(Value::U64(length as u64)));
+            }
+            Segment::Sum => {
+                let values = if definite {
+                    match nodes.first().copied() {
+                        Some(Value::Seq(values)) => values.iter().collect(),
+                        _ => return Ok(None),
+                    }
+                } else {
+                    nodes
+                };
+                let mut sum = Int128::zero();
+                for value in values {
+                    let number = value_to_string(value)
+                        .parse::<Int128>()
+                        .map_err(|_| "Cannot sum values that are not numbers")?;
+                    sum = sum.checked_add(number).map_err(|_| "Sum overflow")?;
+                }
+                return Ok(Some(Value::String(sum.to_string())));
+            }
+        };
+    }
+    if definite {
+        Ok(nodes.first().map(|value| (*value).clone()))
+    } else {
+        Ok(Some(Value::Seq(nodes.into_iter().cloned().collect())))
+    }
+}
+
+fn child<'a>(value: &'a Value, key: &str) -> Option<&'a Value> {
+    match value {
+        Value::Map(map) => map.get(&Value::String(key.to_string())),
+        Value::Seq(values) => values.get(key.parse::<usize>().ok()?),
+        _ => None,
+    }
+}
+
+fn children(value: &Value) -> Vec<&Value> {
+    match value {
+        Value::Map(map) => map.values().collect(),
+        Value::Seq(values) => values.iter().collect(),
+        _ => vec![],
+    }
+}
+
+fn matches_filter(value: &Value, filter: &Filter) -> bool {
+    let Some(field) = filter
+        .field
+        .iter()
+        .try_fold(value, |value, key| child(value, key))
+    else {
+        return false;
+    };
+    let field = value_to_string(field);
+    if let (Ok(left), Ok(right)) = (field.parse::<Int128>(), filter.value.parse::<Int128>()) {
+        return match filter.op {
+            FilterOp::Eq => left == right,
+            FilterOp::Neq => left != right,
+            FilterOp::Lt => left < right,
+            FilterOp::Lte => left <= right,
+            FilterOp::Gt => left > right,
+            FilterOp::Gte => left >= right,
+        };
+    }
+    match filter.op {
+        FilterOp::Eq => field == filter.value,
+        FilterOp::Neq => field != filter.value,
+        // Strings are only compared for equality
+        _ => false,
+    }
+}
+
+fn value_to_string(value: &Value) -> String {
+    match value {
+        Value::String(value) => value.clone(),
+        _ => to_string(value).unwrap_or_default(),
+    }
+}
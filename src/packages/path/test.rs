@@ -0,0 +1,105 @@
+#[cfg(test)]
+mod tests {
+    use serde_cw_value::Value;
+
+    use crate::packages::path::path::{parse_path, select, Filter, FilterOp, Segment};
+
+    fn json(value: &str) -> Value {
+        serde_json_wasm::from_str(value).unwrap()
+    }
+
+    fn select_path(value: &Value, path: &str) -> Result<Option<Value>, &'static str> {
+        select(value, &parse_path(path)?)
+    }
+
+    fn string(value: &str) -> Option<Value> {
+        Some(Value::String(value.to_string()))
+    }
+
+    #[test]
+    fn test_parse_path() {
+        assert_eq!(
+            parse_path("balances[?denom=='uatom'].amount"),
+            Ok(vec![
+                Segment::Key("balances".to_string()),
+                Segment::Filter(Filter {
+                    field: vec!["denom".to_string()],
+                    op: FilterOp::Eq,
+                    value: "uatom".to_string(),
+                }),
+                Segment::Key("amount".to_string()),
+            ])
+        );
+        assert_eq!(
+            parse_path("a[0][*]['b.c'].length()"),
+            Ok(vec![
+                Segment::Key("a".to_string()),
+                Segment::Index(0),
+                Segment::Wildcard,
+                Segment::Key("b.c".to_string()),
+                Segment::Length,
+            ])
+        );
+
+        assert_eq!(parse_path(""), Err("Empty path"));
+        assert_eq!(parse_path("a..b"), Err("Empty path segment"));
+        assert_eq!(parse_path("a."), Err("Empty path segment"));
+        assert_eq!(parse_path("a[0"), Err("Unclosed bracket"));
+        assert_eq!(parse_path("a[x]"), Err("Invalid bracket"));
+        assert_eq!(parse_path("a[?denom]"), Err("Invalid filter"));
+        assert_eq!(parse_path("a[?denom==uatom]"), Err("Invalid filter value"));
+        assert_eq!(
+            parse_path("a.sum().b"),
+            Err("Aggregates must be the last segment")
+        );
+    }
+
+    #[test]
+    fn test_select() {
+        let value = json(
+            r#"{"balances":[{"denom":"uatom","amount":"5"},{"denom":"uosmo","amount":"7"}],"height":10}"#,
+        );
+
+        // Dotted keys keep working, numbers index into arrays
+        assert_eq!(select_path(&value, "height"), Ok(Some(Value::U64(10))));
+        assert_eq!(select_path(&value, "balances.1.denom"), Ok(string("uosmo")));
+        assert_eq!(select_path(&value, "balances[0].amount"), Ok(string("5")));
+        assert_eq!(select_path(&value, "balances[2].amount"), Ok(None));
+        assert_eq!(select_path(&value, "missing"), Ok(None));
+
+        // Filters and wildcards
+        assert_eq!(
+            select_path(&value, "balances[?denom=='uatom'].amount"),
+            Ok(Some(Value::Seq(vec![Value::String("5".to_string())])))
+        );
+        assert_eq!(
+            select_path(&value, "balances[?amount>6].denom"),
+            Ok(Some(Value::Seq(vec![Value::String("uosmo".to_string())])))
+        );
+        assert_eq!(
+            select_path(&value, "balances[*].denom"),
+            Ok(Some(Value::Seq(vec![
+                Value::String("uatom".to_string()),
+                Value::String("uosmo".to_string())
+            ])))
+        );
+
+        // Aggregates
+        assert_eq!(
+            select_path(&value, "balances.length()"),
+            Ok(Some(Value::U64(2)))
+        );
+        assert_eq!(
+            select_path(&value, "balances[?denom=='ujuno'].length()"),
+            Ok(Some(Value::U64(0)))
+        );
+        assert_eq!(
+            select_path(&value, "balances[*].amount.sum()"),
+            Ok(string("12"))
+        );
+        assert_eq!(
+            select_path(&value, "balances[*].denom.sum()"),
+            Err("Cannot sum values that are not numbers")
+        );
+    }
+}
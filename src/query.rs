@@ -1,189 +1,900 @@
-use std::{collections::HashMap, str::from_utf8};
+use std::{collections::HashMap, str::FromStr};
 
-use cosmwasm_std::{from_slice, Deps, Env, Int128, WasmQuery};
+use andromeda_std::common::context::ExecuteContext;
+use cosmwasm_std::{
+    ensure, from_slice, to_binary, Addr, AllBalanceResponse, AllDelegationsResponse, BankQuery,
+    Binary, BondedDenomResponse, DelegationResponse, Deps, Empty, Env, Int128, Order, QueryRequest,
+    StakingQuery, StdError, StdResult, SupplyResponse, Uint128, ValidatorResponse,
+};
+use cw20::{AllowanceResponse, BalanceResponse, Cw20QueryMsg, TokenInfoResponse};
+use cw721::{Cw721QueryMsg, OperatorsResponse, OwnerOfResponse, TokensResponse};
 use cw_json::JSON;
+use cw_storage_plus::Bound;
+use serde::{de::DeserializeOwned, Serialize};
 use serde_cw_value::{to_value, Value};
 use serde_json_wasm::to_string;
 
 use crate::{
-    packages::eval::eval::{evaluate, Tokens},
-    state::{CONDITION, VARIABLES},
+    error::ContractError,
+    evaluation::Evaluation,
+    execute::pay_for_fire,
+    msg::{BatchItemResult, ConditionStatus, ExplainResponse, FireableResponse, FrozenResponse},
+    packages::{
+        eval::eval::{evaluate, Tokens},
+        path::path::{parse_path, select, Segment},
+    },
+    state::{
+        load_condition, ALL_FROZEN, CONDITION, CONDITIONS, CONDITION_HISTORY, FROZEN,
+        PENDING_CONDITIONS, TRIGGERS, VARIABLES,
+    },
+    template::fill_template,
     types::{
-        Condition, ConditionCompare, ConditionCtx, ConditionWing, CurrentQueryCtx, ExternalQuery,
-        ExternalQueryMsg, InwardExecuteCtx, Variable,
+        BatchItem, Condition, ConditionCompare, ConditionCtx, ConditionRecord, ConditionWing,
+        CurrentQueryCtx, Cw20TokenInfoField, ExternalQuery, ExternalQueryMsg,
+        ExternalQueryStorageMsg, InwardExecuteCtx, Overrides, RawDecode, StorageKey,
+        StorageKeyType, Variable,
     },
 };
 
 /** Queries */
 
+const DEFAULT_LIMIT: u32 = 10;
+const MAX_LIMIT: u32 = 30;
+/// Page size used when listing the tokens or operators of a cw721 owner
+const CW721_PAGE_LIMIT: u32 = 100;
+
+/// Triggers are evaluated against the query ctx, so conditions that require a
+/// verified ctx are never listed. Only `limit` triggers are scanned, as each of them
+/// can make as many queries as the limits allow.
+pub fn list_fireable(
+    deps: &Deps,
+    env: Env,
+    start_after: Option<u64>,
+    limit: Option<u32>,
+) -> StdResult<FireableResponse> {
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT) as usize;
+    let ctx = create_condition_ctx(env, None);
+    // Triggers often share variables, so they are resolved once for the whole page
+    let mut eval = Evaluation::new(deps.storage)?;
+    let mut trigger_ids = vec![];
+    let mut last_scanned = None;
+    for item in TRIGGERS
+        .range(
+            deps.storage,
+            start_after.map(Bound::exclusive),
+            None,
+            Order::Ascending,
+        )
+        .take(limit)
+    {
+        let (trigger_id, mut info) = item?;
+        last_scanned = Some(trigger_id);
+        // Firing fails when the balance cannot pay for it
+        if pay_for_fire(&mut info).is_err() {
+            continue;
+        }
+        if let Some(condition) = CONDITIONS.may_load(deps.storage, &info.trigger.condition_name)? {
+            // Each trigger gets the full limits, only the cache is shared
+            eval.reset_limits();
+            // Conditions that fail to evaluate cannot be fired either
+            if matches!(
+                evaluate_condition_with(deps, &ctx, &mut eval, condition),
+                Ok(true)
+            ) {
+                trigger_ids.push(trigger_id);
+            }
+        }
+    }
+    Ok(FireableResponse {
+        trigger_ids,
+        last_scanned,
+    })
+}
+
+pub fn condition_history(
+    deps: &Deps,
+    name: Option<&str>,
+    start_after: Option<u32>,
+    limit: Option<u32>,
+) -> StdResult<Vec<(u32, ConditionRecord)>> {
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT) as usize;
+    CONDITION_HISTORY
+        .prefix(name.unwrap_or_default())
+        .range(
+            deps.storage,
+            start_after.map(Bound::exclusive),
+            None,
+            Order::Ascending,
+        )
+        .take(limit)
+        .collect()
+}
+
+pub fn condition_status(deps: &Deps, name: Option<&str>) -> StdResult<ConditionStatus> {
+    let active = match name {
+        Some(name) => CONDITIONS.may_load(deps.storage, name)?,
+        None => CONDITION.may_load(deps.storage)?,
+    };
+    Ok(ConditionStatus {
+        active,
+        pending: PENDING_CONDITIONS.may_load(deps.storage, name.unwrap_or_default())?,
+    })
+}
+
+pub fn frozen(
+    deps: &Deps,
+    start_after: Option<String>,
+    limit: Option<u32>,
+) -> StdResult<FrozenResponse> {
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT) as usize;
+    Ok(FrozenResponse {
+        all: ALL_FROZEN.may_load(deps.storage)?.unwrap_or_default(),
+        names: FROZEN
+            .keys(
+                deps.storage,
+                start_after.as_deref().map(Bound::exclusive),
+                None,
+                Order::Ascending,
+            )
+            .take(limit)
+            .collect::<StdResult<_>>()?,
+    })
+}
+
+pub fn evaluate_condition_version(
+    deps: &Deps,
+    ctx: &JSON,
+    name: Option<&str>,
+    version: u32,
+) -> Result<bool, ContractError> {
+    let record = CONDITION_HISTORY.load(deps.storage, (name.unwrap_or_default(), version))?;
+    evaluate_condition(deps, ctx, record.condition)
+}
+
 /** Utilities */
 
+/// The address that started the message, which differs from the sender for AMP packets
+pub fn original_sender(ctx: &ExecuteContext) -> Addr {
+    match &ctx.amp_ctx {
+        Some(pkt) => Addr::unchecked(pkt.ctx.get_origin()),
+        None => ctx.info.sender.clone(),
+    }
+}
+
 pub fn create_condition_ctx(env: Env, execute_ctx: Option<InwardExecuteCtx>) -> JSON {
+    build_condition_ctx(env, execute_ctx, false)
+}
+
+/// Builds the ctx from the message actually being executed. Sender and funds come from
+/// `MessageInfo` and the original sender from the AMP packet origin, so the ctx is verified.
+pub fn create_verified_condition_ctx(ctx: &ExecuteContext, msg: Binary) -> JSON {
+    let original_sender = original_sender(ctx);
+    let execute_ctx = InwardExecuteCtx {
+        env: ctx.env.clone(),
+        msg,
+        funds: ctx.info.funds.clone(),
+        sender: ctx.info.sender.clone(),
+        original_sender,
+    };
+    build_condition_ctx(ctx.env.clone(), Some(execute_ctx), true)
+}
+
+fn build_condition_ctx(env: Env, execute_ctx: Option<InwardExecuteCtx>, verified: bool) -> JSON {
     let ctx = ConditionCtx {
         execute_ctx: execute_ctx.clone(),
         query_ctx: CurrentQueryCtx { env: env },
+        verified,
     };
     let mut ctx = JSON::from_any(ctx);
 
-    // Parse the msg binary to json so it can also be used for references
-    match execute_ctx {
-        Some(exc_ctx) => {
-            ctx.update("execute_ctx.msg", to_value(&exc_ctx.msg).unwrap())
-                .unwrap();
+    // Parse the msg binary to json so it can also be used for references. Messages that
+    // are not json are left as their base64 encoding.
+    if let Some(exc_ctx) = execute_ctx {
+        if let Ok(msg) = from_slice::<Value>(&exc_ctx.msg) {
+            ctx.update("execute_ctx.msg", msg).unwrap();
         }
-        None => {}
-    };
+    }
     ctx
 }
 
-pub fn evaluate_default_condition(deps: &Deps, ctx: &JSON) -> bool {
-    let condition = CONDITION.load(deps.storage).unwrap();
-    evaluate_condition(&deps, &ctx, condition)
+pub fn evaluate_stored_condition_with(
+    deps: &Deps,
+    ctx: &JSON,
+    eval: &mut Evaluation,
+    name: Option<&str>,
+) -> Result<bool, ContractError> {
+    let condition = load_condition(deps.storage, name)?;
+    evaluate_condition_with(deps, ctx, eval, condition)
+}
+
+pub fn evaluate_batch(
+    deps: &Deps,
+    ctx: &JSON,
+    items: Vec<BatchItem>,
+) -> Result<Vec<BatchItemResult>, ContractError> {
+    let mut eval = Evaluation::new(deps.storage)?;
+    Ok(items
+        .into_iter()
+        .map(|item| {
+            // Items share the cache but not the limits
+            eval.reset_limits();
+            let result = match item {
+                BatchItem::Condition { name } => {
+                    evaluate_stored_condition_with(deps, ctx, &mut eval, name.as_deref())
+                        .map(BatchItemResult::Condition)
+                }
+                BatchItem::CustomCondition { condition } => {
+                    evaluate_condition_with(deps, ctx, &mut eval, condition)
+                        .map(BatchItemResult::Condition)
+                }
+                BatchItem::Variable { name } => evaluate_token_with(deps, ctx, &mut eval, &name)
+                    .map(|value| BatchItemResult::Variable(value.map(value_to_string))),
+                BatchItem::CustomVariable { variable } => {
+                    evaluate_variable_with(deps, ctx, &mut eval, &variable)
+                        .map(|value| BatchItemResult::Variable(value.map(value_to_string)))
+                }
+            };
+            result.unwrap_or_else(|err| BatchItemResult::Error(err.to_string()))
+        })
+        .collect())
 }
 
-pub fn evaluate_condition(deps: &Deps, ctx: &JSON, condition: Condition) -> bool {
-    let left = match condition.left {
-        ConditionWing::Expression(tokens) => evaluate_expressions(deps, ctx, &tokens),
-        ConditionWing::Number(v) => Some(Value::String(v.to_string())),
-        ConditionWing::String(v) => Some(Value::String(v)),
-        ConditionWing::Bool(v) => Some(Value::Bool(v)),
-        ConditionWing::Condition(c) => Some(Value::Bool(evaluate_condition(deps, ctx, *c))),
+/// Applies the overrides of a query. Keys naming a stored variable replace its value,
+/// any other key is set in the ctx at its path and can also be read as a variable.
+pub fn apply_overrides(
+    deps: &Deps,
+    ctx: &mut JSON,
+    eval: &mut Evaluation,
+    overrides: Overrides,
+) -> Result<(), ContractError> {
+    for (key, value) in overrides {
+        // Query ctxs are supplied by the querier and must never pass as verified
+        ensure!(
+            key != "verified" && !key.starts_with("verified."),
+            ContractError::InvalidOverride { key }
+        );
+        if !VARIABLES.has(deps.storage, &key) {
+            ctx.update(&key, value.clone())
+                .map_err(|_| ContractError::InvalidOverride { key: key.clone() })?;
+        }
+        eval.set_override(&key, value);
     }
-    .unwrap();
-    let left = match left.clone() {
-        Value::String(v) => v,
-        _ => to_string(&left).unwrap(),
-    };
-    println!("LEFT = {left:?}");
-    let right = match condition.right {
-        ConditionWing::Expression(tokens) => evaluate_expressions(deps, ctx, &tokens),
-        ConditionWing::Number(v) => Some(Value::String(v.to_string())),
-        ConditionWing::String(v) => Some(Value::String(v)),
-        ConditionWing::Bool(v) => Some(Value::Bool(v)),
-        ConditionWing::Condition(c) => Some(Value::Bool(evaluate_condition(deps, ctx, *c))),
-    }
-    .unwrap();
-    let right = match right.clone() {
-        Value::String(v) => v,
-        _ => to_string(&right).unwrap(),
-    };
-    println!("RIGHT = {right:?}");
+    Ok(())
+}
 
-    if left.parse::<Int128>().is_ok() && right.parse::<Int128>().is_ok() {
-        let left = left.parse::<Int128>().unwrap();
-        let right = right.parse::<Int128>().unwrap();
-        match condition.compare {
+/// Evaluates a stored condition and reports what was resolved to get the result
+pub fn explain_stored_condition(
+    deps: &Deps,
+    ctx: &JSON,
+    name: Option<&str>,
+) -> Result<ExplainResponse, ContractError> {
+    let condition = load_condition(deps.storage, name)?;
+    let mut eval = Evaluation::new(deps.storage)?;
+    let result = evaluate_condition_with(deps, ctx, &mut eval, condition)?;
+    Ok(ExplainResponse {
+        result,
+        variables: eval
+            .resolved_variables()
+            .map(|(name, value)| (name.to_string(), value.cloned().map(value_to_string)))
+            .collect(),
+        external_queries: eval.external_queries,
+        cache_hits: eval.cache_hits,
+    })
+}
+
+pub fn evaluate_condition(
+    deps: &Deps,
+    ctx: &JSON,
+    condition: Condition,
+) -> Result<bool, ContractError> {
+    evaluate_condition_with(deps, ctx, &mut Evaluation::new(deps.storage)?, condition)
+}
+
+pub fn evaluate_condition_with(
+    deps: &Deps,
+    ctx: &JSON,
+    eval: &mut Evaluation,
+    condition: Condition,
+) -> Result<bool, ContractError> {
+    eval.enter()?;
+    let result = compare_condition(deps, ctx, eval, condition);
+    eval.exit();
+    result
+}
+
+fn compare_condition(
+    deps: &Deps,
+    ctx: &JSON,
+    eval: &mut Evaluation,
+    condition: Condition,
+) -> Result<bool, ContractError> {
+    if condition.require_verified.unwrap_or(false) && !is_verified_ctx(ctx) {
+        return Ok(false);
+    }
+    let left = value_to_string(evaluate_wing(deps, ctx, eval, condition.left)?);
+    let right = value_to_string(evaluate_wing(deps, ctx, eval, condition.right)?);
+
+    if let (Ok(left), Ok(right)) = (left.parse::<Int128>(), right.parse::<Int128>()) {
+        Ok(match condition.compare {
             ConditionCompare::Eq => left.eq(&right),
             ConditionCompare::Neq => left.ne(&right),
             ConditionCompare::Lt => left.lt(&right),
             ConditionCompare::Lte => left.le(&right),
             ConditionCompare::Gt => left.gt(&right),
             ConditionCompare::Gte => left.ge(&right),
-        }
+        })
     } else {
         match condition.compare {
-            ConditionCompare::Eq => left.eq(&right),
-            ConditionCompare::Neq => left.ne(&right),
+            ConditionCompare::Eq => Ok(left.eq(&right)),
+            ConditionCompare::Neq => Ok(left.ne(&right)),
             // All other conditions are not valid for this type of operator
-            _ => Err("Invalid operator for non integer types").unwrap(),
+            _ => Err(ContractError::InvalidOperator {}),
         }
     }
 }
 
-fn evaluate_expressions(deps: &Deps, ctx: &JSON, tokens: &Tokens) -> Option<Value> {
-    if tokens.len() == 1 {
-        return match evaluate_token(deps, ctx, tokens.first().unwrap()) {
-            Some(value) => Some(value),
-            None => Some(to_value(tokens.first().unwrap()).unwrap()),
-        };
+fn evaluate_wing(
+    deps: &Deps,
+    ctx: &JSON,
+    eval: &mut Evaluation,
+    wing: ConditionWing,
+) -> Result<Value, ContractError> {
+    Ok(match wing {
+        ConditionWing::Expression(tokens) => evaluate_expressions(deps, ctx, eval, &tokens)?,
+        ConditionWing::Number(v) => Value::String(v.to_string()),
+        ConditionWing::String(v) => Value::String(v),
+        ConditionWing::Bool(v) => Value::Bool(v),
+        ConditionWing::Condition(c) => Value::Bool(evaluate_condition_with(deps, ctx, eval, *c)?),
+    })
+}
+
+/// Strings are used as is so numbers stored as strings, like `Int128`, can be parsed
+fn value_to_string(value: Value) -> String {
+    match value {
+        Value::String(v) => v,
+        _ => to_string(&value).unwrap(),
+    }
+}
+
+fn is_verified_ctx(ctx: &JSON) -> bool {
+    matches!(ctx.get("verified"), Some(Value::Bool(true)))
+}
+
+fn evaluate_expressions(
+    deps: &Deps,
+    ctx: &JSON,
+    eval: &mut Evaluation,
+    tokens: &Tokens,
+) -> Result<Value, ContractError> {
+    eval.check_expression(tokens.len())?;
+    if let [token] = tokens.as_slice() {
+        // A single token that is not a variable is used as a literal
+        return Ok(evaluate_token_with(deps, ctx, eval, token)?
+            .unwrap_or_else(|| Value::String(token.clone())));
     }
     let mut variables = HashMap::<&str, Int128>::new();
-    tokens
-        .iter()
-        .for_each(|token| match evaluate_token(deps, ctx, token) {
-            Some(value) => {
-                variables.insert(&token, to_string(&value).unwrap().parse().unwrap());
-            }
-            None => {}
-        });
-    Some(to_value(evaluate(tokens.clone(), &variables).unwrap().to_string()).unwrap())
+    for token in tokens {
+        if let Some(value) = evaluate_token_with(deps, ctx, eval, token)? {
+            let value = value_to_string(value);
+            let number = value
+                .parse::<Int128>()
+                .map_err(|_| ContractError::NotANumber {
+                    name: token.clone(),
+                    value,
+                })?;
+            variables.insert(token.as_str(), number);
+        }
+    }
+    let result =
+        evaluate(tokens.clone(), &variables).map_err(|msg| ContractError::InvalidExpression {
+            msg: msg.to_string(),
+        })?;
+    Ok(Value::String(result.to_string()))
 }
 
-pub fn evaluate_token(deps: &Deps, ctx: &JSON, token: &String) -> Option<Value> {
-    if let Some(variable) = VARIABLES.may_load(deps.storage, &token).unwrap() {
-        match evaluate_variable(deps, ctx, &variable) {
-            Some(value) => Some(value),
-            None => None,
+/// Resolves a named variable once per evaluation, `None` when no variable has that name
+pub fn evaluate_token_with(
+    deps: &Deps,
+    ctx: &JSON,
+    eval: &mut Evaluation,
+    token: &str,
+) -> Result<Option<Value>, ContractError> {
+    if let Some(value) = eval.overridden(token) {
+        return Ok(Some(value));
+    }
+    if let Some(value) = eval.variable(token) {
+        return Ok(value);
+    }
+    match VARIABLES.may_load(deps.storage, token)? {
+        Some(variable) => {
+            let value = evaluate_variable_with(deps, ctx, eval, &variable)?;
+            eval.set_variable(token, value.clone());
+            Ok(value)
         }
-    } else {
-        None
+        None => Ok(None),
     }
 }
 
-pub fn evaluate_variable(deps: &Deps, ctx: &JSON, variable: &Variable) -> Option<Value> {
+/// Resolves a variable, `None` when the value it points at does not exist
+pub fn evaluate_variable(
+    deps: &Deps,
+    ctx: &JSON,
+    variable: &Variable,
+) -> Result<Option<Value>, ContractError> {
+    evaluate_variable_with(deps, ctx, &mut Evaluation::new(deps.storage)?, variable)
+}
+
+pub fn evaluate_variable_with(
+    deps: &Deps,
+    ctx: &JSON,
+    eval: &mut Evaluation,
+    variable: &Variable,
+) -> Result<Option<Value>, ContractError> {
+    eval.enter()?;
+    let value = resolve_variable(deps, ctx, eval, variable);
+    eval.exit();
+    value
+}
+
+fn resolve_variable(
+    deps: &Deps,
+    ctx: &JSON,
+    eval: &mut Evaluation,
+    variable: &Variable,
+) -> Result<Option<Value>, ContractError> {
     match variable {
-        Variable::Raw(raw) => Some(to_value(raw).unwrap()),
-        Variable::Reference(reference) => ctx.get(reference.as_str()).and_then(|v| Some(v.clone())),
-        Variable::Query(query) => {
-            match evaluate_query(deps, ctx, query) {
-                Some(value) => {
-                    match &query.result {
-                        Some(key) => {
-                            // Key is provided, we need to fetch the key from the json or any value returned
-                            let json = JSON::from(value);
-                            json.get(&key).and_then(|v| Some(v.clone()))
-                        }
-                        None => Some(value),
-                    }
+        Variable::Raw(raw) => Ok(Some(Value::String(raw.clone()))),
+        Variable::Reference(reference) => resolve_reference(ctx, reference),
+        Variable::Expression(tokens) => evaluate_expressions(deps, ctx, eval, tokens).map(Some),
+        Variable::Query(query) => match evaluate_query(deps, ctx, eval, query)? {
+            Some(value) => match &query.result {
+                Some(path) => select_path(&value, &parse_path_checked(path)?, path),
+                None => Ok(Some(value)),
+            },
+            None => Ok(None),
+        },
+        Variable::Coalesce(variables) => {
+            // Failing variables are skipped the same as missing ones, going over a limit
+            // still fails the evaluation
+            for variable in variables {
+                match evaluate_variable_with(deps, ctx, eval, variable) {
+                    Ok(Some(value)) => return Ok(Some(value)),
+                    Err(err @ ContractError::LimitExceeded { .. }) => return Err(err),
+                    _ => {}
                 }
-                _ => None,
             }
-        } // _ => None,
-    }
-}
-
-fn evaluate_query(deps: &Deps, ctx: &JSON, query: &ExternalQuery) -> Option<Value> {
-    match evaluate_variable(deps, ctx, &query.contract).unwrap() {
-        Value::String(contract) => {
-            let address = deps.api.addr_validate(&contract.as_str()).unwrap();
-            match &query.query {
-                ExternalQueryMsg::Raw(msg) => {
-                    match deps
-                        .querier
-                        .query_wasm_raw(address.clone(), msg.key.as_bytes())
-                    {
-                        Ok(data) => match data {
-                            Some(data) => {
-                                let res = from_utf8(&data).unwrap();
-                                let res = from_slice(res.as_bytes()).unwrap();
-                                return Some(res);
-                            }
-                            None => {
-                                return None;
-                            }
-                        },
-                        Err(err) => Err(format!(
-                            "Smart query failed for contract {address} with error - {err:?}"
-                        ))
-                        .unwrap(),
-                    }
-                }
-                ExternalQueryMsg::Smart(msg) => {
-                    let query_msg = WasmQuery::Smart {
-                        contract_addr: address.to_string(),
-                        msg: msg.msg.clone(),
-                    }
-                    .into();
-                    match deps.querier.query(&query_msg) {
-                        Ok(data) => Some(data),
-                        Err(err) => Err(format!(
-                            "Smart query failed for contract {address} and msg {msg:?} with error - {err:?}"
-                        ))
-                        .unwrap(),
-                    }
+            Ok(None)
+        }
+        Variable::WithDefault { variable, default } => {
+            match evaluate_variable_with(deps, ctx, eval, variable) {
+                Ok(Some(value)) => Ok(Some(value)),
+                Err(err @ ContractError::LimitExceeded { .. }) => Err(err),
+                _ => Ok(Some(Value::String(default.clone()))),
+            }
+        }
+        Variable::Balance { address, denom } => {
+            let address = resolve_address(deps, ctx, eval, address)?;
+            let res: cosmwasm_std::BalanceResponse = query_chain(
+                deps,
+                eval,
+                BankQuery::Balance {
+                    address: address.to_string(),
+                    denom: denom.clone(),
+                },
+            )?;
+            Ok(Some(Value::String(res.amount.amount.to_string())))
+        }
+        Variable::AllBalances { address } => {
+            let address = resolve_address(deps, ctx, eval, address)?;
+            let res: AllBalanceResponse = query_chain(
+                deps,
+                eval,
+                BankQuery::AllBalances {
+                    address: address.to_string(),
+                },
+            )?;
+            let balances =
+                to_value(res.amount).map_err(|err| StdError::generic_err(err.to_string()))?;
+            Ok(Some(balances))
+        }
+        Variable::Supply { denom } => {
+            let res: SupplyResponse = query_chain(
+                deps,
+                eval,
+                BankQuery::Supply {
+                    denom: denom.clone(),
+                },
+            )?;
+            Ok(Some(Value::String(res.amount.amount.to_string())))
+        }
+        Variable::Cw20Balance { token, address } => {
+            let token = resolve_address(deps, ctx, eval, token)?;
+            let address = resolve_address(deps, ctx, eval, address)?;
+            let res: BalanceResponse = query_smart(
+                deps,
+                eval,
+                &token,
+                &Cw20QueryMsg::Balance {
+                    address: address.to_string(),
+                },
+            )?;
+            Ok(Some(Value::String(res.balance.to_string())))
+        }
+        Variable::Cw20Allowance {
+            token,
+            owner,
+            spender,
+        } => {
+            let token = resolve_address(deps, ctx, eval, token)?;
+            let owner = resolve_address(deps, ctx, eval, owner)?;
+            let spender = resolve_address(deps, ctx, eval, spender)?;
+            let res: AllowanceResponse = query_smart(
+                deps,
+                eval,
+                &token,
+                &Cw20QueryMsg::Allowance {
+                    owner: owner.to_string(),
+                    spender: spender.to_string(),
+                },
+            )?;
+            Ok(Some(Value::String(res.allowance.to_string())))
+        }
+        Variable::Cw20TokenInfo { token, field } => {
+            let token = resolve_address(deps, ctx, eval, token)?;
+            let res: TokenInfoResponse =
+                query_smart(deps, eval, &token, &Cw20QueryMsg::TokenInfo {})?;
+            Ok(Some(Value::String(match field {
+                Cw20TokenInfoField::Name => res.name,
+                Cw20TokenInfoField::Symbol => res.symbol,
+                Cw20TokenInfoField::Decimals => res.decimals.to_string(),
+                Cw20TokenInfoField::TotalSupply => res.total_supply.to_string(),
+            })))
+        }
+        Variable::Cw721OwnerOf {
+            collection,
+            token_id,
+        } => {
+            let collection = resolve_address(deps, ctx, eval, collection)?;
+            let token_id = resolve_string(deps, ctx, eval, token_id)?;
+            let res = query_cw721_owner(deps, eval, &collection, token_id)?;
+            Ok(Some(Value::String(res.owner)))
+        }
+        Variable::Cw721Tokens { collection, owner } => {
+            let collection = resolve_address(deps, ctx, eval, collection)?;
+            let owner = resolve_address(deps, ctx, eval, owner)?;
+            let mut count = 0u64;
+            let mut start_after = None;
+            loop {
+                let res: TokensResponse = query_smart(
+                    deps,
+                    eval,
+                    &collection,
+                    &Cw721QueryMsg::Tokens {
+                        owner: owner.to_string(),
+                        start_after,
+                        limit: Some(CW721_PAGE_LIMIT),
+                    },
+                )?;
+                count += res.tokens.len() as u64;
+                if res.tokens.len() < CW721_PAGE_LIMIT as usize {
+                    break;
                 }
+                start_after = res.tokens.last().cloned();
             }
+            Ok(Some(Value::String(count.to_string())))
+        }
+        Variable::Cw721IsApproved {
+            collection,
+            token_id,
+            spender,
+        } => {
+            let collection = resolve_address(deps, ctx, eval, collection)?;
+            let token_id = resolve_string(deps, ctx, eval, token_id)?;
+            let spender = resolve_address(deps, ctx, eval, spender)?;
+            let res = query_cw721_owner(deps, eval, &collection, token_id)?;
+            let approved = res.owner == spender
+                || res
+                    .approvals
+                    .iter()
+                    .any(|approval| approval.spender == spender)
+                || query_cw721_operator(deps, eval, &collection, res.owner, &spender)?;
+            Ok(Some(Value::Bool(approved)))
+        }
+        Variable::Delegation {
+            delegator,
+            validator,
+        } => {
+            let delegator = resolve_address(deps, ctx, eval, delegator)?;
+            let validator = resolve_string(deps, ctx, eval, validator)?;
+            let res: DelegationResponse = query_chain(
+                deps,
+                eval,
+                StakingQuery::Delegation {
+                    delegator: delegator.to_string(),
+                    validator,
+                },
+            )?;
+            let amount = res
+                .delegation
+                .map(|delegation| delegation.amount.amount)
+                .unwrap_or_default();
+            Ok(Some(Value::String(amount.to_string())))
+        }
+        Variable::TotalDelegated { delegator } => {
+            let delegator = resolve_address(deps, ctx, eval, delegator)?;
+            let res: AllDelegationsResponse = query_chain(
+                deps,
+                eval,
+                StakingQuery::AllDelegations {
+                    delegator: delegator.to_string(),
+                },
+            )?;
+            let amount: Uint128 = res
+                .delegations
+                .iter()
+                .map(|delegation| delegation.amount.amount)
+                .sum();
+            Ok(Some(Value::String(amount.to_string())))
+        }
+        Variable::BondedDenom => {
+            let res: BondedDenomResponse = query_chain(deps, eval, StakingQuery::BondedDenom {})?;
+            Ok(Some(Value::String(res.denom)))
+        }
+        Variable::IsActiveValidator { validator } => {
+            let validator = resolve_string(deps, ctx, eval, validator)?;
+            // Validators outside the active set are not returned
+            let res: ValidatorResponse =
+                query_chain(deps, eval, StakingQuery::Validator { address: validator })?;
+            let active = res.validator.is_some();
+            Ok(Some(Value::Bool(active)))
+        }
+    }
+}
+
+fn query_cw721_owner(
+    deps: &Deps,
+    eval: &mut Evaluation,
+    collection: &Addr,
+    token_id: String,
+) -> Result<OwnerOfResponse, ContractError> {
+    query_smart(
+        deps,
+        eval,
+        collection,
+        &Cw721QueryMsg::OwnerOf {
+            token_id,
+            include_expired: Some(false),
+        },
+    )
+}
+
+fn query_smart<T: DeserializeOwned>(
+    deps: &Deps,
+    eval: &mut Evaluation,
+    contract: &Addr,
+    msg: &impl Serialize,
+) -> Result<T, ContractError> {
+    eval.smart_query(deps, contract, to_binary(msg)?)?
+        .deserialize_into()
+        .map_err(|err| ContractError::ExternalQueryFailed {
+            contract: contract.to_string(),
+            msg: err.to_string(),
+        })
+}
+
+/// Bank and staking queries fail like the querier helpers do, not as external queries
+fn query_chain<T: DeserializeOwned>(
+    deps: &Deps,
+    eval: &mut Evaluation,
+    request: impl Into<QueryRequest<Empty>>,
+) -> Result<T, ContractError> {
+    eval.chain_query(deps, request.into())?
+        .deserialize_into()
+        .map_err(|err| StdError::generic_err(err.to_string()).into())
+}
+
+/// Whether `operator` may transfer all tokens of `owner`. Operators are listed, as
+/// collections older than cw721 0.18 cannot be queried for a single operator.
+fn query_cw721_operator(
+    deps: &Deps,
+    eval: &mut Evaluation,
+    collection: &Addr,
+    owner: String,
+    operator: &Addr,
+) -> Result<bool, ContractError> {
+    let mut start_after = None;
+    loop {
+        let res: OperatorsResponse = query_smart(
+            deps,
+            eval,
+            collection,
+            &Cw721QueryMsg::AllOperators {
+                owner: owner.clone(),
+                include_expired: Some(false),
+                start_after,
+                limit: Some(CW721_PAGE_LIMIT),
+            },
+        )?;
+        if res
+            .operators
+            .iter()
+            .any(|approval| approval.spender == *operator)
+        {
+            return Ok(true);
+        }
+        if res.operators.len() < CW721_PAGE_LIMIT as usize {
+            return Ok(false);
+        }
+        start_after = res
+            .operators
+            .last()
+            .map(|approval| approval.spender.clone());
+    }
+}
+
+pub fn parse_path_checked(path: &str) -> Result<Vec<Segment>, ContractError> {
+    parse_path(path).map_err(|msg| ContractError::InvalidPath {
+        path: path.to_string(),
+        msg: msg.to_string(),
+    })
+}
+
+fn select_path(
+    value: &Value,
+    segments: &[Segment],
+    path: &str,
+) -> Result<Option<Value>, ContractError> {
+    select(value, segments).map_err(|msg| ContractError::InvalidPath {
+        path: path.to_string(),
+        msg: msg.to_string(),
+    })
+}
+
+/// Reads a path from the ctx. The leading keys are looked up in the ctx json and the
+/// rest of the path is resolved on the value found there.
+pub fn resolve_reference(ctx: &JSON, reference: &str) -> Result<Option<Value>, ContractError> {
+    let segments = parse_path_checked(reference)?;
+    let keys = segments
+        .iter()
+        .map_while(|segment| match segment {
+            Segment::Key(key) if !key.contains('.') => Some(key.as_str()),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    if keys.is_empty() {
+        return Err(ContractError::InvalidPath {
+            path: reference.to_string(),
+            msg: "Path must start with a key".to_string(),
+        });
+    }
+    match ctx.get(keys.join(".").as_str()) {
+        Some(value) => select_path(value, &segments[keys.len()..], reference),
+        None => Ok(None),
+    }
+}
+
+fn resolve_string(
+    deps: &Deps,
+    ctx: &JSON,
+    eval: &mut Evaluation,
+    variable: &Variable,
+) -> Result<String, ContractError> {
+    evaluate_variable_with(deps, ctx, eval, variable)?
+        .map(value_to_string)
+        .ok_or(ContractError::MissingValue {})
+}
+
+fn resolve_address(
+    deps: &Deps,
+    ctx: &JSON,
+    eval: &mut Evaluation,
+    variable: &Variable,
+) -> Result<Addr, ContractError> {
+    match evaluate_variable_with(deps, ctx, eval, variable)? {
+        Some(Value::String(address)) => Ok(deps.api.addr_validate(&address)?),
+        _ => Err(ContractError::InvalidAddress {}),
+    }
+}
+
+fn evaluate_query(
+    deps: &Deps,
+    ctx: &JSON,
+    eval: &mut Evaluation,
+    query: &ExternalQuery,
+) -> Result<Option<Value>, ContractError> {
+    let address = resolve_address(deps, ctx, eval, &query.contract)?;
+    let (key, decode) = match &query.query {
+        ExternalQueryMsg::Raw(msg) => (msg.key.as_bytes().to_vec(), &msg.decode),
+        ExternalQueryMsg::Storage(msg) => (storage_key(deps, ctx, eval, msg)?, &msg.decode),
+        ExternalQueryMsg::Smart(msg) => {
+            return Ok(Some(eval.smart_query(deps, &address, msg.msg.clone())?));
+        }
+        ExternalQueryMsg::Template(msg) => {
+            let msg = fill_template(deps, ctx, eval, &msg.msg)?;
+            return Ok(Some(eval.smart_query(deps, &address, msg)?));
+        }
+    };
+    match eval.raw_query(deps, &address, Binary::from(key))? {
+        Some(data) => Ok(Some(decode_raw(
+            &data,
+            decode.as_ref().unwrap_or(&RawDecode::Json),
+        )?)),
+        None => Ok(None),
+    }
+}
+
+fn decode_raw(data: &[u8], decode: &RawDecode) -> Result<Value, ContractError> {
+    let invalid = || ContractError::InvalidRawData {
+        decode: format!("{decode:?}"),
+    };
+    Ok(match decode {
+        RawDecode::Json => from_slice::<Value>(data).map_err(|_| invalid())?,
+        RawDecode::Utf8 => Value::String(String::from_utf8(data.to_vec()).map_err(|_| invalid())?),
+        RawDecode::U64Be => {
+            Value::String(u64::from_be_bytes(data.try_into().map_err(|_| invalid())?).to_string())
         }
-        _ => Err("Invalid contract for query").unwrap(),
+        RawDecode::U128Be => {
+            Value::String(u128::from_be_bytes(data.try_into().map_err(|_| invalid())?).to_string())
+        }
+        RawDecode::Base64 => Value::String(Binary::from(data).to_base64()),
+        RawDecode::Hex => Value::String(data.iter().map(|byte| format!("{byte:02x}")).collect()),
+    })
+}
+
+/// Encodes a key like cw-storage-plus: an `Item` is stored under its namespace, a `Map`
+/// entry under the length prefixed namespace and leading keys followed by the last key
+fn storage_key(
+    deps: &Deps,
+    ctx: &JSON,
+    eval: &mut Evaluation,
+    msg: &ExternalQueryStorageMsg,
+) -> Result<Vec<u8>, ContractError> {
+    let mut segments = vec![msg.namespace.as_bytes().to_vec()];
+    for key in &msg.keys {
+        segments.push(storage_key_segment(deps, ctx, eval, key)?);
+    }
+    let last = segments.pop().unwrap_or_default();
+    let mut key = vec![];
+    for segment in segments {
+        key.extend_from_slice(&(segment.len() as u16).to_be_bytes());
+        key.extend(segment);
+    }
+    key.extend(last);
+    Ok(key)
+}
+
+fn storage_key_segment(
+    deps: &Deps,
+    ctx: &JSON,
+    eval: &mut Evaluation,
+    key: &StorageKey,
+) -> Result<Vec<u8>, ContractError> {
+    if key.key_type == StorageKeyType::Addr {
+        return Ok(resolve_address(deps, ctx, eval, &key.value)?
+            .as_bytes()
+            .to_vec());
+    }
+    let value = resolve_string(deps, ctx, eval, &key.value)?;
+    fn parse<T: FromStr>(key_type: &StorageKeyType, value: &str) -> Result<T, ContractError> {
+        value.parse().map_err(|_| ContractError::InvalidStorageKey {
+            key_type: format!("{key_type:?}"),
+            value: value.to_string(),
+        })
     }
+    let key_type = &key.key_type;
+    Ok(match key_type {
+        StorageKeyType::String | StorageKeyType::Addr => value.into_bytes(),
+        StorageKeyType::U8 => parse::<u8>(key_type, &value)?.to_be_bytes().to_vec(),
+        StorageKeyType::U16 => parse::<u16>(key_type, &value)?.to_be_bytes().to_vec(),
+        StorageKeyType::U32 => parse::<u32>(key_type, &value)?.to_be_bytes().to_vec(),
+        StorageKeyType::U64 => parse::<u64>(key_type, &value)?.to_be_bytes().to_vec(),
+        StorageKeyType::U128 => parse::<u128>(key_type, &value)?.to_be_bytes().to_vec(),
+        // Signed keys have their sign bit flipped so they sort in order
+        StorageKeyType::I32 => (parse::<i32>(key_type, &value)? as u32 ^ i32::MIN as u32)
+            .to_be_bytes()
+            .to_vec(),
+        StorageKeyType::I64 => (parse::<i64>(key_type, &value)? as u64 ^ i64::MIN as u64)
+            .to_be_bytes()
+            .to_vec(),
+    })
 }
@@ -1,7 +1,98 @@
+use cosmwasm_std::{Addr, BlockInfo, Empty, Order, StdResult, Storage};
 use cw_storage_plus::{Item, Map};
 
-use crate::types::{Condition, Variable};
+use crate::types::{
+    Condition, ConditionRecord, Escrow, EvaluationLimits, PendingCondition, ProxyConfig,
+    TriggerInfo, Variable,
+};
 
 pub const VARIABLES: Map<&str, Variable> = Map::new("variables");
 
+/// Names each variable reads directly
+pub const DEPENDENCIES: Map<&str, Vec<String>> = Map::new("dependencies");
+
 pub const CONDITION: Item<Condition> = Item::new("condition");
+
+pub const CONDITIONS: Map<&str, Condition> = Map::new("conditions");
+
+/// Every version a condition was set to, starting at 1. The default condition is kept
+/// under an empty name, which named conditions cannot have.
+pub const CONDITION_HISTORY: Map<(&str, u32), ConditionRecord> = Map::new("condition_history");
+
+/// Seconds a proposed condition change waits before it can be applied. Conditions can
+/// only be changed through proposals while it is set.
+pub const TIMELOCK: Item<u64> = Item::new("timelock");
+
+/// At most one proposal per condition, under the same names as the condition history
+pub const PENDING_CONDITIONS: Map<&str, PendingCondition> = Map::new("pending_conditions");
+
+/// Names of the conditions and variables that can never change again, the default
+/// condition under an empty name as in the condition history
+pub const FROZEN: Map<&str, Empty> = Map::new("frozen");
+
+/// Set once every condition and variable is frozen, the default condition included
+pub const ALL_FROZEN: Item<bool> = Item::new("all_frozen");
+
+pub const PROXY: Item<ProxyConfig> = Item::new("proxy");
+
+pub const ESCROWS: Map<u64, Escrow> = Map::new("escrows");
+
+pub const NEXT_ESCROW_ID: Item<u64> = Item::new("next_escrow_id");
+
+pub const TRIGGERS: Map<u64, TriggerInfo> = Map::new("triggers");
+
+pub const NEXT_TRIGGER_ID: Item<u64> = Item::new("next_trigger_id");
+
+/// Defaults apply until limits are set
+pub const LIMITS: Item<EvaluationLimits> = Item::new("limits");
+
+/// Loads a named condition, or the default condition when no name is given
+pub fn load_condition(storage: &dyn Storage, name: Option<&str>) -> StdResult<Condition> {
+    match name {
+        Some(name) => CONDITIONS.load(storage, name),
+        None => CONDITION.load(storage),
+    }
+}
+
+/// Whether the condition or variable `name` is frozen, the default condition when no
+/// name is given
+pub fn is_frozen(storage: &dyn Storage, name: Option<&str>) -> StdResult<bool> {
+    if ALL_FROZEN.may_load(storage)?.unwrap_or_default() {
+        return Ok(true);
+    }
+    Ok(FROZEN.has(storage, name.unwrap_or_default()))
+}
+
+/// Sets a named condition, or the default condition when no name is given, and records
+/// it as the next version of that condition. Returns the new version.
+pub fn save_condition(
+    storage: &mut dyn Storage,
+    block: &BlockInfo,
+    author: &Addr,
+    name: Option<&str>,
+    condition: &Condition,
+) -> StdResult<u32> {
+    match name {
+        Some(name) => CONDITIONS.save(storage, name, condition)?,
+        None => CONDITION.save(storage, condition)?,
+    }
+    let history_name = name.unwrap_or_default();
+    let version = CONDITION_HISTORY
+        .prefix(history_name)
+        .keys(storage, None, None, Order::Descending)
+        .next()
+        .transpose()?
+        .unwrap_or_default()
+        + 1;
+    CONDITION_HISTORY.save(
+        storage,
+        (history_name, version),
+        &ConditionRecord {
+            condition: condition.clone(),
+            author: author.clone(),
+            height: block.height,
+            time: block.time,
+        },
+    )?;
+    Ok(version)
+}
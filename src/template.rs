@@ -0,0 +1,113 @@
+use cosmwasm_std::{from_slice, to_binary, Binary, Deps};
+use cw_json::JSON;
+use serde_cw_value::Value;
+
+use crate::{
+    error::ContractError,
+    evaluation::Evaluation,
+    query::{evaluate_token_with, resolve_reference},
+    state::VARIABLES,
+};
+
+const VAR: &str = "$var";
+const REF: &str = "$ref";
+
+/// A `{"$var": name}` or `{"$ref": path}` object inside a query template
+pub enum Placeholder {
+    Var(String),
+    Ref(String),
+}
+
+/// Placeholders of a json template together with the path they are found at
+pub fn template_placeholders(msg: &Binary) -> Result<Vec<(String, Placeholder)>, ContractError> {
+    let value = from_slice::<Value>(msg).map_err(|err| ContractError::InvalidTemplate {
+        msg: err.to_string(),
+    })?;
+    let mut placeholders = vec![];
+    collect_placeholders(&value, "msg".to_string(), &mut placeholders)?;
+    Ok(placeholders)
+}
+
+fn collect_placeholders(
+    value: &Value,
+    path: String,
+    placeholders: &mut Vec<(String, Placeholder)>,
+) -> Result<(), ContractError> {
+    match value {
+        Value::Map(map) => {
+            if let Some(placeholder) = as_placeholder(value)? {
+                placeholders.push((path, placeholder));
+                return Ok(());
+            }
+            for (key, value) in map {
+                let Value::String(key) = key else {
+                    continue;
+                };
+                collect_placeholders(value, format!("{path}.{key}"), placeholders)?;
+            }
+        }
+        Value::Seq(values) => {
+            for (idx, value) in values.iter().enumerate() {
+                collect_placeholders(value, format!("{path}.{idx}"), placeholders)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+fn as_placeholder(value: &Value) -> Result<Option<Placeholder>, ContractError> {
+    let Value::Map(map) = value else {
+        return Ok(None);
+    };
+    if map.len() != 1 {
+        return Ok(None);
+    }
+    let Some((Value::String(key), target)) = map.iter().next() else {
+        return Ok(None);
+    };
+    if key != VAR && key != REF {
+        return Ok(None);
+    }
+    let Value::String(target) = target else {
+        return Err(ContractError::InvalidTemplate {
+            msg: format!("{key} must be a string"),
+        });
+    };
+    Ok(Some(if key == VAR {
+        Placeholder::Var(target.clone())
+    } else {
+        Placeholder::Ref(target.clone())
+    }))
+}
+
+/// Replaces every placeholder of the template with its current value. The template is
+/// nested under `msg` so the filled message can be read back from the json.
+pub fn fill_template(
+    deps: &Deps,
+    ctx: &JSON,
+    eval: &mut Evaluation,
+    msg: &Binary,
+) -> Result<Binary, ContractError> {
+    let placeholders = template_placeholders(msg)?;
+    let mut json = JSON::from(Value::Map(
+        [(Value::String("msg".to_string()), from_slice::<Value>(msg)?)].into(),
+    ));
+    for (path, placeholder) in placeholders {
+        let value = match placeholder {
+            Placeholder::Var(name) => {
+                if !VARIABLES.has(deps.storage, &name) {
+                    return Err(ContractError::UndefinedName { name });
+                }
+                evaluate_token_with(deps, ctx, eval, &name)?
+            }
+            Placeholder::Ref(reference) => resolve_reference(ctx, &reference)?,
+        }
+        .ok_or(ContractError::MissingValue {})?;
+        json.update(&path, value)
+            .map_err(|_| ContractError::InvalidTemplate {
+                msg: format!("cannot set {path}"),
+            })?;
+    }
+    Ok(to_binary(&json.get("msg").cloned().unwrap_or(Value::Unit))?)
+}
@@ -1,13 +1,52 @@
 #[cfg(test)]
 mod test {
     use crate::{
-        query::{create_condition_ctx, evaluate_condition},
-        state::VARIABLES,
-        types::{Condition, InwardExecuteCtx, Variable},
+        contract::{instantiate, migrate, query},
+        dependencies::transitive_dependencies,
+        error::ContractError as ConditionalError,
+        evaluation::Evaluation,
+        execute::{
+            add_condition, add_trigger, add_variable, apply_condition, cancel_proposal, deposit,
+            fire, forward, freeze, freeze_all, propose_condition, refund, release, set_limits,
+            set_proxy, set_timelock,
+        },
+        msg::{
+            BatchItemResult, ConditionStatus, Diagnostic, ExplainResponse, FireableResponse,
+            FrozenResponse, InstantiateMsg, MigrateMsg, QueryMsg,
+        },
+        query::{
+            create_condition_ctx, create_verified_condition_ctx, evaluate_condition,
+            evaluate_token_with, evaluate_variable, evaluate_variable_with,
+            explain_stored_condition, list_fireable,
+        },
+        state::{CONDITION, CONDITIONS, ESCROWS, LIMITS, PROXY, TRIGGERS, VARIABLES},
+        types::{
+            BatchItem, Condition, ConditionRecord, Cw20TokenInfoField, EvaluationLimits,
+            ExternalQuery, ExternalQueryMsg, ExternalQueryRawMsg, ExternalQuerySmartMsg,
+            ExternalQueryStorageMsg, ExternalQueryTemplateMsg, InwardExecuteCtx, Overrides,
+            PendingCondition, ProxyConfig, RawDecode, StorageKey, StorageKeyType, Trigger,
+            Variable,
+        },
     };
-    use andromeda_std::common::encode_binary;
-    use cosmwasm_std::testing::{mock_dependencies, mock_env};
-    use cosmwasm_std::Addr;
+    use andromeda_std::{
+        amp::messages::AMPPkt,
+        common::{context::ExecuteContext, encode_binary},
+        error::ContractError,
+    };
+    use cosmwasm_std::testing::{mock_dependencies, mock_env, mock_info, MockStorage};
+    use cosmwasm_std::{
+        coin, coins, from_binary, to_binary, Addr, BankMsg, Binary, Coin, ContractResult,
+        CosmosMsg, Decimal, Deps, StakingMsg, Storage, SystemError, SystemResult, Uint128,
+        Validator, WasmMsg, WasmQuery,
+    };
+    use cw2::set_contract_version;
+    use cw20::{AllowanceResponse, BalanceResponse, Cw20QueryMsg, TokenInfoResponse};
+    use cw721::{Approval, Cw721QueryMsg, OperatorsResponse, OwnerOfResponse, TokensResponse};
+    use cw_multi_test::{App, Executor, StakingInfo};
+    use cw_storage_plus::{Item, Map};
+    use cw_utils::Expiration;
+    use serde_cw_value::Value;
+    use std::{cell::Cell, collections::BTreeSet, rc::Rc};
 
     #[test]
     fn test_evaluate_condition() {
@@ -18,8 +57,9 @@ mod test {
             left: crate::types::ConditionWing::String("10".to_string()),
             right: crate::types::ConditionWing::Number(10.into()),
             compare: crate::types::ConditionCompare::Eq,
+            require_verified: None,
         };
-        let res = evaluate_condition(&deps.as_ref(), &condition_ctx, condition);
+        let res = evaluate_condition(&deps.as_ref(), &condition_ctx, condition).unwrap();
         assert_eq!(res, true);
     }
 
@@ -50,8 +90,9 @@ mod test {
             ]),
             right: crate::types::ConditionWing::Expression(vec!["variable".to_string()]),
             compare: crate::types::ConditionCompare::Gt,
+            require_verified: None,
         };
-        let res = evaluate_condition(&deps.as_ref(), &condition_ctx, condition);
+        let res = evaluate_condition(&deps.as_ref(), &condition_ctx, condition).unwrap();
         assert_eq!(res, true);
     }
 
@@ -81,8 +122,2599 @@ mod test {
             ]),
             right: crate::types::ConditionWing::Expression(vec!["variable".to_string()]),
             compare: crate::types::ConditionCompare::Eq,
+            require_verified: None,
         };
-        let res = evaluate_condition(&deps.as_ref(), &condition_ctx, condition);
+        let res = evaluate_condition(&deps.as_ref(), &condition_ctx, condition).unwrap();
         assert_eq!(res, false);
     }
+
+    #[test]
+    fn test_evaluate_condition_require_verified() {
+        let mut deps = mock_dependencies();
+        let env = mock_env();
+
+        let variable = Variable::Reference("execute_ctx.sender".to_string());
+        VARIABLES
+            .save(deps.as_mut().storage, "sender", &variable)
+            .unwrap();
+
+        let condition = Condition {
+            left: crate::types::ConditionWing::Expression(vec!["sender".to_string()]),
+            right: crate::types::ConditionWing::String("admin".to_string()),
+            compare: crate::types::ConditionCompare::Eq,
+            require_verified: Some(true),
+        };
+
+        // A querier can claim any sender, so the ctx it supplies is never verified
+        let forged_ctx = InwardExecuteCtx {
+            env: env.clone(),
+            msg: encode_binary(&"".to_string()).unwrap(),
+            funds: vec![],
+            sender: Addr::unchecked("admin"),
+            original_sender: Addr::unchecked("admin"),
+        };
+        let condition_ctx = create_condition_ctx(env.clone(), Some(forged_ctx));
+        let res = evaluate_condition(&deps.as_ref(), &condition_ctx, condition.clone()).unwrap();
+        assert!(!res);
+
+        let ctx = ExecuteContext::new(deps.as_mut(), mock_info("admin", &[]), env);
+        let condition_ctx =
+            create_verified_condition_ctx(&ctx, encode_binary(&"".to_string()).unwrap());
+        let res = evaluate_condition(&ctx.deps.as_ref(), &condition_ctx, condition).unwrap();
+        assert!(res);
+    }
+
+    #[test]
+    fn test_forward() {
+        let mut deps = mock_dependencies();
+        let env = mock_env();
+
+        let variable = Variable::Reference("execute_ctx.msg.transfer.amount".to_string());
+        VARIABLES
+            .save(deps.as_mut().storage, "amount", &variable)
+            .unwrap();
+        // Only transfers of at most 100 are let through
+        let condition = Condition {
+            left: crate::types::ConditionWing::Expression(vec!["amount".to_string()]),
+            right: crate::types::ConditionWing::Number(100.into()),
+            compare: crate::types::ConditionCompare::Lte,
+            require_verified: Some(true),
+        };
+        CONDITION.save(deps.as_mut().storage, &condition).unwrap();
+        PROXY
+            .save(
+                deps.as_mut().storage,
+                &ProxyConfig {
+                    target: Addr::unchecked("target"),
+                    allowed_msgs: Some(vec!["transfer".to_string()]),
+                },
+            )
+            .unwrap();
+
+        let msg = Binary::from(r#"{"transfer":{"recipient":"user","amount":"50"}}"#.as_bytes());
+        let ctx = ExecuteContext::new(deps.as_mut(), mock_info("sender", &[]), env.clone());
+        let res = forward(ctx, msg.clone(), vec![]).unwrap();
+        assert_eq!(
+            res.messages[0].msg,
+            CosmosMsg::Wasm(WasmMsg::Execute {
+                contract_addr: "target".to_string(),
+                msg,
+                funds: vec![],
+            })
+        );
+
+        let msg = Binary::from(r#"{"transfer":{"recipient":"user","amount":"500"}}"#.as_bytes());
+        let ctx = ExecuteContext::new(deps.as_mut(), mock_info("sender", &[]), env.clone());
+        let err = forward(ctx, msg, vec![]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            ContractError::from(ConditionalError::ConditionNotMet {}).to_string()
+        );
+
+        // Forwarded funds have to match the sent funds exactly, duplicate denoms included
+        let msg = Binary::from(r#"{"transfer":{"recipient":"user","amount":"50"}}"#.as_bytes());
+        let ctx = ExecuteContext::new(
+            deps.as_mut(),
+            mock_info("sender", &coins(100, "uatom")),
+            env.clone(),
+        );
+        let err = forward(
+            ctx,
+            msg.clone(),
+            vec![coin(100, "uatom"), coin(100, "uatom")],
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            ContractError::from(ConditionalError::FundsMismatch {}).to_string()
+        );
+        let ctx = ExecuteContext::new(
+            deps.as_mut(),
+            mock_info("sender", &coins(100, "uatom")),
+            env.clone(),
+        );
+        let err = forward(ctx, msg.clone(), vec![]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            ContractError::from(ConditionalError::FundsMismatch {}).to_string()
+        );
+        let ctx = ExecuteContext::new(
+            deps.as_mut(),
+            mock_info("sender", &coins(100, "uatom")),
+            env.clone(),
+        );
+        forward(ctx, msg, vec![coin(60, "uatom"), coin(40, "uatom")]).unwrap();
+
+        let msg = Binary::from(r#"{"burn":{"amount":"50"}}"#.as_bytes());
+        let ctx = ExecuteContext::new(deps.as_mut(), mock_info("sender", &[]), env);
+        let err = forward(ctx, msg, vec![]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            ContractError::from(ConditionalError::ForwardNotAllowed {
+                msg_type: "burn".to_string()
+            })
+            .to_string()
+        );
+    }
+
+    #[test]
+    fn test_escrow() {
+        let mut deps = mock_dependencies();
+        let mut env = mock_env();
+
+        let variable = Variable::Reference("query_ctx.env.block.height".to_string());
+        VARIABLES
+            .save(deps.as_mut().storage, "height", &variable)
+            .unwrap();
+        let condition = Condition {
+            left: crate::types::ConditionWing::Expression(vec!["height".to_string()]),
+            right: crate::types::ConditionWing::Number((env.block.height as i128 + 10).into()),
+            compare: crate::types::ConditionCompare::Gte,
+            require_verified: None,
+        };
+        CONDITIONS
+            .save(deps.as_mut().storage, "matured", &condition)
+            .unwrap();
+
+        let ctx = ExecuteContext::new(
+            deps.as_mut(),
+            mock_info("depositor", &coins(100, "uusd")),
+            env.clone(),
+        );
+        let err = deposit(
+            ctx,
+            "recipient".to_string(),
+            "unknown".to_string(),
+            None,
+            Expiration::Never {},
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            ContractError::from(ConditionalError::ConditionNotFound {
+                name: "unknown".to_string()
+            })
+            .to_string()
+        );
+
+        let ctx = ExecuteContext::new(
+            deps.as_mut(),
+            mock_info("depositor", &coins(100, "uusd")),
+            env.clone(),
+        );
+        deposit(
+            ctx,
+            "recipient".to_string(),
+            "matured".to_string(),
+            None,
+            Expiration::AtHeight(env.block.height + 20),
+        )
+        .unwrap();
+
+        // Neither released nor refunded before the condition holds or the escrow expires
+        let ctx = ExecuteContext::new(deps.as_mut(), mock_info("anyone", &[]), env.clone());
+        let err = release(ctx, 0).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            ContractError::from(ConditionalError::ConditionNotMet {}).to_string()
+        );
+        let ctx = ExecuteContext::new(deps.as_mut(), mock_info("depositor", &[]), env.clone());
+        let err = refund(ctx, 0).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            ContractError::from(ConditionalError::RefundNotAllowed {}).to_string()
+        );
+
+        env.block.height += 10;
+        let ctx = ExecuteContext::new(deps.as_mut(), mock_info("anyone", &[]), env.clone());
+        let res = release(ctx, 0).unwrap();
+        assert_eq!(
+            res.messages[0].msg,
+            CosmosMsg::Bank(BankMsg::Send {
+                to_address: "recipient".to_string(),
+                amount: coins(100, "uusd"),
+            })
+        );
+        assert!(!ESCROWS.has(deps.as_ref().storage, 0));
+
+        // Expired escrows are returned to the depositor
+        let ctx = ExecuteContext::new(
+            deps.as_mut(),
+            mock_info("depositor", &coins(50, "uusd")),
+            env.clone(),
+        );
+        deposit(
+            ctx,
+            "recipient".to_string(),
+            "matured".to_string(),
+            None,
+            Expiration::AtHeight(env.block.height + 1),
+        )
+        .unwrap();
+        env.block.height += 1;
+        let ctx = ExecuteContext::new(deps.as_mut(), mock_info("anyone", &[]), env);
+        let res = refund(ctx, 1).unwrap();
+        assert_eq!(
+            res.messages[0].msg,
+            CosmosMsg::Bank(BankMsg::Send {
+                to_address: "depositor".to_string(),
+                amount: coins(50, "uusd"),
+            })
+        );
+    }
+
+    #[test]
+    fn test_trigger() {
+        let mut deps = mock_dependencies();
+        let mut env = mock_env();
+        instantiate(
+            deps.as_mut(),
+            env.clone(),
+            mock_info("owner", &[]),
+            InstantiateMsg {
+                kernel_address: "kernel".to_string(),
+                owner: None,
+                variables: vec![],
+                condition: None,
+                conditions: vec![],
+                limits: None,
+                timelock: None,
+            },
+        )
+        .unwrap();
+
+        let variable = Variable::Reference("query_ctx.env.block.height".to_string());
+        VARIABLES
+            .save(deps.as_mut().storage, "height", &variable)
+            .unwrap();
+        let condition = Condition {
+            left: crate::types::ConditionWing::Expression(vec!["height".to_string()]),
+            right: crate::types::ConditionWing::Number((env.block.height as i128 + 10).into()),
+            compare: crate::types::ConditionCompare::Gte,
+            require_verified: None,
+        };
+        CONDITIONS
+            .save(deps.as_mut().storage, "matured", &condition)
+            .unwrap();
+
+        let trigger = Trigger {
+            condition_name: "matured".to_string(),
+            msgs: vec![BankMsg::Send {
+                to_address: "target".to_string(),
+                amount: coins(1, "uatom"),
+            }
+            .into()],
+            reward: coins(5, "uusd"),
+            one_shot: false,
+        };
+        let ctx = ExecuteContext::new(
+            deps.as_mut(),
+            mock_info("anyone", &coins(10, "uusd")),
+            env.clone(),
+        );
+        let err = add_trigger(ctx, trigger.clone()).unwrap_err();
+        assert_eq!(err.to_string(), ContractError::Unauthorized {}.to_string());
+
+        // Funds the msgs send are paid from the trigger balance too
+        let ctx = ExecuteContext::new(
+            deps.as_mut(),
+            mock_info("owner", &[coin(2, "uatom"), coin(10, "uusd")]),
+            env.clone(),
+        );
+        add_trigger(ctx, trigger.clone()).unwrap();
+
+        let fireable = list_fireable(&deps.as_ref(), env.clone(), None, None).unwrap();
+        assert!(fireable.trigger_ids.is_empty());
+        let ctx = ExecuteContext::new(deps.as_mut(), mock_info("keeper", &[]), env.clone());
+        let err = fire(ctx, 0).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            ContractError::from(ConditionalError::ConditionNotMet {}).to_string()
+        );
+
+        env.block.height += 10;
+        let fireable = list_fireable(&deps.as_ref(), env.clone(), None, None).unwrap();
+        assert_eq!(fireable.trigger_ids, vec![0]);
+        let ctx = ExecuteContext::new(deps.as_mut(), mock_info("keeper", &[]), env.clone());
+        let res = fire(ctx, 0).unwrap();
+        assert_eq!(
+            res.messages[0].msg,
+            CosmosMsg::Bank(BankMsg::Send {
+                to_address: "target".to_string(),
+                amount: coins(1, "uatom"),
+            })
+        );
+        assert_eq!(
+            res.messages[1].msg,
+            CosmosMsg::Bank(BankMsg::Send {
+                to_address: "keeper".to_string(),
+                amount: coins(5, "uusd"),
+            })
+        );
+
+        // The second fire empties the balance, so a third fire cannot be paid for
+        let ctx = ExecuteContext::new(deps.as_mut(), mock_info("keeper", &[]), env.clone());
+        fire(ctx, 0).unwrap();
+        let info = TRIGGERS.load(deps.as_ref().storage, 0).unwrap();
+        assert!(info.balance.is_empty());
+        let ctx = ExecuteContext::new(deps.as_mut(), mock_info("keeper", &[]), env.clone());
+        let err = fire(ctx, 0).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            ContractError::from(ConditionalError::InsufficientTriggerBalance {}).to_string()
+        );
+
+        // A trigger without a reward still cannot send more than it was funded with
+        let ctx = ExecuteContext::new(
+            deps.as_mut(),
+            mock_info("owner", &coins(10, "uusd")),
+            env.clone(),
+        );
+        add_trigger(
+            ctx,
+            Trigger {
+                reward: vec![],
+                ..trigger
+            },
+        )
+        .unwrap();
+        let ctx = ExecuteContext::new(deps.as_mut(), mock_info("keeper", &[]), env.clone());
+        let err = fire(ctx, 1).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            ContractError::from(ConditionalError::InsufficientTriggerBalance {}).to_string()
+        );
+        // Neither trigger is listed, their condition holds but they cannot pay
+        let fireable = list_fireable(&deps.as_ref(), env, None, None).unwrap();
+        assert_eq!(
+            fireable,
+            FireableResponse {
+                trigger_ids: vec![],
+                last_scanned: Some(1),
+            }
+        );
+    }
+
+    #[test]
+    fn test_instantiate_with_conditions() {
+        let mut deps = mock_dependencies();
+        let env = mock_env();
+        let height = Variable::Reference("query_ctx.env.block.height".to_string());
+        let condition = Condition {
+            left: crate::types::ConditionWing::Expression(vec!["height".to_string()]),
+            right: crate::types::ConditionWing::Number(0.into()),
+            compare: crate::types::ConditionCompare::Gt,
+            require_verified: None,
+        };
+
+        let err = instantiate(
+            deps.as_mut(),
+            env.clone(),
+            mock_info("owner", &[]),
+            InstantiateMsg {
+                kernel_address: "kernel".to_string(),
+                owner: None,
+                variables: vec![
+                    ("height".to_string(), height.clone()),
+                    ("height".to_string(), height.clone()),
+                ],
+                condition: Some(condition.clone()),
+                conditions: vec![],
+                limits: None,
+                timelock: None,
+            },
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            ContractError::from(ConditionalError::DuplicateName {
+                name: "height".to_string()
+            })
+            .to_string()
+        );
+
+        let mut deps = mock_dependencies();
+        instantiate(
+            deps.as_mut(),
+            env.clone(),
+            mock_info("owner", &[]),
+            InstantiateMsg {
+                kernel_address: "kernel".to_string(),
+                owner: None,
+                variables: vec![("height".to_string(), height)],
+                condition: Some(condition.clone()),
+                conditions: vec![("positive_height".to_string(), condition)],
+                limits: None,
+                timelock: None,
+            },
+        )
+        .unwrap();
+
+        for name in [None, Some("positive_height".to_string())] {
+            let res = query(
+                deps.as_ref(),
+                env.clone(),
+                QueryMsg::Evaluate {
+                    name,
+                    ctx: None,
+                    overrides: Overrides::new(),
+                },
+            );
+            assert!(from_binary::<bool>(&res.unwrap()).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_migrate_old_format() {
+        let mut deps = mock_dependencies();
+        set_contract_version(deps.as_mut().storage, "crates.io:conditional-ado", "0.1.0").unwrap();
+
+        // Written by 0.1.0, before conditions had `require_verified`
+        deps.as_mut().storage.set(
+            b"condition",
+            br#"{"left":{"condition":{"left":{"expression":["height"]},"right":{"number":"10"},"compare":"gt"}},"right":{"bool":true},"compare":"eq"}"#,
+        );
+        deps.as_mut().storage.set(
+            &VARIABLES.key("height"),
+            br#"{"reference":"query_ctx.env.block.height"}"#,
+        );
+        deps.as_mut().storage.set(
+            &CONDITIONS.key("named"),
+            br#"{"left":{"bool":true},"right":{"bool":true},"compare":"eq"}"#,
+        );
+
+        migrate(deps.as_mut(), mock_env(), MigrateMsg {}).unwrap();
+
+        let stored = deps.as_ref().storage.get(b"condition").unwrap();
+        assert_eq!(
+            String::from_utf8(stored)
+                .unwrap()
+                .matches("require_verified")
+                .count(),
+            2
+        );
+        let condition = CONDITION.load(deps.as_ref().storage).unwrap();
+        assert_eq!(
+            condition,
+            Condition {
+                left: crate::types::ConditionWing::Condition(Box::new(Condition {
+                    left: crate::types::ConditionWing::Expression(vec!["height".to_string()]),
+                    right: crate::types::ConditionWing::Number(10.into()),
+                    compare: crate::types::ConditionCompare::Gt,
+                    require_verified: None,
+                })),
+                right: crate::types::ConditionWing::Bool(true),
+                compare: crate::types::ConditionCompare::Eq,
+                require_verified: None,
+            }
+        );
+        assert_eq!(
+            VARIABLES.load(deps.as_ref().storage, "height").unwrap(),
+            Variable::Reference("query_ctx.env.block.height".to_string())
+        );
+
+        // Conditions stored before the history existed become its first version
+        let res: Vec<(u32, ConditionRecord)> = from_binary(
+            &query(
+                deps.as_ref(),
+                mock_env(),
+                QueryMsg::ConditionHistory {
+                    name: Some("named".to_string()),
+                    start_after: None,
+                    limit: None,
+                },
+            )
+            .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(res.len(), 1);
+        assert_eq!(res[0].0, 1);
+        assert_eq!(res[0].1.author, mock_env().contract.address);
+        let res: Vec<(u32, ConditionRecord)> = from_binary(
+            &query(
+                deps.as_ref(),
+                mock_env(),
+                QueryMsg::ConditionHistory {
+                    name: None,
+                    start_after: None,
+                    limit: None,
+                },
+            )
+            .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(
+            res,
+            vec![(
+                1,
+                ConditionRecord {
+                    condition,
+                    author: mock_env().contract.address,
+                    height: mock_env().block.height,
+                    time: mock_env().block.time,
+                }
+            )]
+        );
+    }
+
+    #[test]
+    fn test_migrate_invalid_version() {
+        let mut deps = mock_dependencies();
+        set_contract_version(deps.as_mut().storage, "crates.io:other-contract", "0.1.0").unwrap();
+        let err = migrate(deps.as_mut(), mock_env(), MigrateMsg {}).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            ContractError::from(ConditionalError::WrongContract {
+                contract: "crates.io:other-contract".to_string()
+            })
+            .to_string()
+        );
+
+        set_contract_version(deps.as_mut().storage, "crates.io:conditional-ado", "99.0.0").unwrap();
+        let err = migrate(deps.as_mut(), mock_env(), MigrateMsg {}).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            ContractError::from(ConditionalError::MigrationDowngrade {
+                stored: "99.0.0".to_string(),
+                current: env!("CARGO_PKG_VERSION").to_string()
+            })
+            .to_string()
+        );
+    }
+
+    #[test]
+    fn test_condition_dependencies() {
+        let mut deps = mock_dependencies();
+        let env = mock_env();
+        let variable = Variable::Reference("query_ctx.env.block.height".to_string());
+        VARIABLES
+            .save(deps.as_mut().storage, "height", &variable)
+            .unwrap();
+
+        let condition = Condition {
+            left: crate::types::ConditionWing::Expression(vec![
+                "height".to_string(),
+                "+".to_string(),
+                "offset".to_string(),
+            ]),
+            right: crate::types::ConditionWing::Number(0.into()),
+            compare: crate::types::ConditionCompare::Gt,
+            require_verified: None,
+        };
+        let ctx = ExecuteContext::new(deps.as_mut(), mock_info("owner", &[]), env.clone());
+        let err = add_condition(ctx, condition, None).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            ContractError::from(ConditionalError::UndefinedName {
+                name: "offset".to_string()
+            })
+            .to_string()
+        );
+
+        // A single token that is not a variable is a literal
+        let condition = Condition {
+            left: crate::types::ConditionWing::Expression(vec!["height".to_string()]),
+            right: crate::types::ConditionWing::Expression(vec!["literal".to_string()]),
+            compare: crate::types::ConditionCompare::Neq,
+            require_verified: None,
+        };
+        let ctx = ExecuteContext::new(deps.as_mut(), mock_info("owner", &[]), env.clone());
+        add_condition(ctx, condition, Some("named".to_string())).unwrap();
+        let res = query(
+            deps.as_ref(),
+            env,
+            QueryMsg::ConditionDependencies {
+                name: Some("named".to_string()),
+            },
+        )
+        .unwrap();
+        assert_eq!(
+            from_binary::<Vec<String>>(&res).unwrap(),
+            vec!["height".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_expression_variable() {
+        let mut deps = mock_dependencies();
+        let env = mock_env();
+        for (name, variable) in [
+            ("collateral", Variable::Raw("2000000".to_string())),
+            ("price", Variable::Raw("3".to_string())),
+            (
+                "collateral_value",
+                Variable::Expression(vec![
+                    "collateral".to_string(),
+                    "*".to_string(),
+                    "price".to_string(),
+                    "/".to_string(),
+                    "1000000".to_string(),
+                ]),
+            ),
+        ] {
+            let ctx = ExecuteContext::new(deps.as_mut(), mock_info("owner", &[]), env.clone());
+            add_variable(ctx, &variable, name).unwrap();
+        }
+
+        let condition_ctx = create_condition_ctx(env.clone(), None);
+        let mut eval = Evaluation::new(deps.as_ref().storage).unwrap();
+        let value = evaluate_token_with(
+            &deps.as_ref(),
+            &condition_ctx,
+            &mut eval,
+            "collateral_value",
+        )
+        .unwrap();
+        assert_eq!(value, Some(Value::String("6".to_string())));
+
+        let condition = Condition {
+            left: crate::types::ConditionWing::Expression(vec![
+                "collateral_value".to_string(),
+                "+".to_string(),
+                "1".to_string(),
+            ]),
+            right: crate::types::ConditionWing::Number(7.into()),
+            compare: crate::types::ConditionCompare::Eq,
+            require_verified: None,
+        };
+        assert!(evaluate_condition(&deps.as_ref(), &condition_ctx, condition).unwrap());
+
+        let res = query(
+            deps.as_ref(),
+            env,
+            QueryMsg::Dependencies {
+                name: "collateral_value".to_string(),
+            },
+        )
+        .unwrap();
+        assert_eq!(
+            from_binary::<Vec<String>>(&res).unwrap(),
+            vec!["collateral".to_string(), "price".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_variable_dependency_cycle() {
+        let mut deps = mock_dependencies();
+        let env = mock_env();
+        let variables = [
+            ("a", Variable::Expression(vec!["1".to_string()])),
+            (
+                "b",
+                Variable::Expression(vec!["a".to_string(), "+".to_string(), "1".to_string()]),
+            ),
+        ];
+        for (name, variable) in variables {
+            let ctx = ExecuteContext::new(deps.as_mut(), mock_info("owner", &[]), env.clone());
+            add_variable(ctx, &variable, name).unwrap();
+        }
+
+        let variable =
+            Variable::Expression(vec!["b".to_string(), "+".to_string(), "1".to_string()]);
+        let ctx = ExecuteContext::new(deps.as_mut(), mock_info("owner", &[]), env.clone());
+        let err = add_variable(ctx, &variable, "a").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            ContractError::from(ConditionalError::DependencyCycle {
+                name: "a".to_string()
+            })
+            .to_string()
+        );
+
+        let variable =
+            Variable::Expression(vec!["c".to_string(), "+".to_string(), "1".to_string()]);
+        let ctx = ExecuteContext::new(deps.as_mut(), mock_info("owner", &[]), env);
+        let err = add_variable(ctx, &variable, "a").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            ContractError::from(ConditionalError::UndefinedName {
+                name: "c".to_string()
+            })
+            .to_string()
+        );
+
+        // A single token names a variable too, so it cannot be defined before it exists
+        let ctx = ExecuteContext::new(deps.as_mut(), mock_info("owner", &[]), env.clone());
+        let err = add_variable(ctx, &Variable::Expression(vec!["y".to_string()]), "x").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            ContractError::from(ConditionalError::UndefinedName {
+                name: "y".to_string()
+            })
+            .to_string()
+        );
+        let ctx = ExecuteContext::new(deps.as_mut(), mock_info("owner", &[]), env.clone());
+        add_variable(ctx, &Variable::Expression(vec!["b".to_string()]), "x").unwrap();
+        assert_eq!(
+            transitive_dependencies(deps.as_ref().storage, "x").unwrap(),
+            BTreeSet::from(["a".to_string(), "b".to_string()])
+        );
+        let ctx = ExecuteContext::new(deps.as_mut(), mock_info("owner", &[]), env);
+        let err = add_variable(ctx, &Variable::Expression(vec!["x".to_string()]), "a").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            ContractError::from(ConditionalError::DependencyCycle {
+                name: "a".to_string()
+            })
+            .to_string()
+        );
+    }
+
+    #[test]
+    fn test_variable_names() {
+        let mut deps = mock_dependencies();
+        let env = mock_env();
+        for name in ["", "100", "-5", "+", "*", "("] {
+            let ctx = ExecuteContext::new(deps.as_mut(), mock_info("owner", &[]), env.clone());
+            let err = add_variable(ctx, &Variable::Raw("1".to_string()), name).unwrap_err();
+            let expected = if name.is_empty() {
+                ConditionalError::EmptyName {}
+            } else {
+                ConditionalError::InvalidName {
+                    name: name.to_string(),
+                }
+            };
+            assert_eq!(err.to_string(), ContractError::from(expected).to_string());
+        }
+        let ctx = ExecuteContext::new(deps.as_mut(), mock_info("owner", &[]), env);
+        add_variable(ctx, &Variable::Raw("1".to_string()), "price_2").unwrap();
+    }
+
+    #[test]
+    fn test_coalesce_variable() {
+        let deps = mock_dependencies();
+        let condition_ctx = create_condition_ctx(mock_env(), None);
+        let failing_query = Variable::Query(Box::new(ExternalQuery {
+            contract: Variable::Raw("oracle".to_string()),
+            query: ExternalQueryMsg::Smart(ExternalQuerySmartMsg {
+                msg: encode_binary(&"price".to_string()).unwrap(),
+            }),
+            result: None,
+        }));
+        let missing_reference = Variable::Reference("execute_ctx.sender".to_string());
+        assert!(evaluate_variable(&deps.as_ref(), &condition_ctx, &failing_query).is_err());
+        assert_eq!(
+            evaluate_variable(&deps.as_ref(), &condition_ctx, &missing_reference).unwrap(),
+            None
+        );
+
+        let variable = Variable::Coalesce(vec![
+            failing_query.clone(),
+            missing_reference.clone(),
+            Variable::Raw("5".to_string()),
+        ]);
+        assert_eq!(
+            evaluate_variable(&deps.as_ref(), &condition_ctx, &variable).unwrap(),
+            Some(Value::String("5".to_string()))
+        );
+
+        let variable = Variable::WithDefault {
+            variable: Box::new(Variable::Coalesce(vec![failing_query, missing_reference])),
+            default: "0".to_string(),
+        };
+        assert_eq!(
+            evaluate_variable(&deps.as_ref(), &condition_ctx, &variable).unwrap(),
+            Some(Value::String("0".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_bank_variables() {
+        let mut deps = mock_dependencies();
+        deps.querier
+            .update_balance("holder", vec![coin(100, "uatom"), coin(5, "uosmo")]);
+        deps.querier.update_balance("other", coins(20, "uatom"));
+        let env = mock_env();
+        let condition_ctx = create_condition_ctx(env.clone(), None);
+
+        let balance = Variable::Balance {
+            address: Box::new(Variable::Raw("holder".to_string())),
+            denom: "uatom".to_string(),
+        };
+        assert_eq!(
+            evaluate_variable(&deps.as_ref(), &condition_ctx, &balance).unwrap(),
+            Some(Value::String("100".to_string()))
+        );
+        let supply = Variable::Supply {
+            denom: "uatom".to_string(),
+        };
+        assert_eq!(
+            evaluate_variable(&deps.as_ref(), &condition_ctx, &supply).unwrap(),
+            Some(Value::String("120".to_string()))
+        );
+        let all_balances = Variable::AllBalances {
+            address: Box::new(Variable::Raw("holder".to_string())),
+        };
+        let value = evaluate_variable(&deps.as_ref(), &condition_ctx, &all_balances)
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            from_binary::<Vec<Coin>>(&encode_binary(&value).unwrap()).unwrap(),
+            vec![coin(100, "uatom"), coin(5, "uosmo")]
+        );
+
+        // Holds at least 50 uatom
+        let ctx = ExecuteContext::new(deps.as_mut(), mock_info("owner", &[]), env.clone());
+        add_variable(ctx, &balance, "holder_balance").unwrap();
+        let condition = Condition {
+            left: crate::types::ConditionWing::Expression(vec!["holder_balance".to_string()]),
+            right: crate::types::ConditionWing::Number(50.into()),
+            compare: crate::types::ConditionCompare::Gte,
+            require_verified: None,
+        };
+        assert!(evaluate_condition(&deps.as_ref(), &condition_ctx, condition).unwrap());
+    }
+
+    #[test]
+    fn test_cw20_variables() {
+        let mut deps = mock_dependencies();
+        deps.querier.update_wasm(|query| match query {
+            WasmQuery::Smart { contract_addr, msg } if contract_addr == "token" => {
+                let res = match from_binary(msg).unwrap() {
+                    Cw20QueryMsg::Balance { address } => to_binary(&BalanceResponse {
+                        balance: if address == "holder" {
+                            Uint128::new(250)
+                        } else {
+                            Uint128::zero()
+                        },
+                    }),
+                    Cw20QueryMsg::Allowance { .. } => to_binary(&AllowanceResponse {
+                        allowance: Uint128::new(40),
+                        expires: Expiration::Never {},
+                    }),
+                    Cw20QueryMsg::TokenInfo {} => to_binary(&TokenInfoResponse {
+                        name: "Token".to_string(),
+                        symbol: "TKN".to_string(),
+                        decimals: 6,
+                        total_supply: Uint128::new(1000),
+                    }),
+                    _ => unimplemented!(),
+                };
+                SystemResult::Ok(ContractResult::Ok(res.unwrap()))
+            }
+            _ => SystemResult::Err(SystemError::NoSuchContract {
+                addr: "unknown".to_string(),
+            }),
+        });
+        let env = mock_env();
+        let condition_ctx = create_condition_ctx(env.clone(), None);
+        let token = Box::new(Variable::Raw("token".to_string()));
+
+        let balance = Variable::Cw20Balance {
+            token: token.clone(),
+            address: Box::new(Variable::Raw("holder".to_string())),
+        };
+        assert_eq!(
+            evaluate_variable(&deps.as_ref(), &condition_ctx, &balance).unwrap(),
+            Some(Value::String("250".to_string()))
+        );
+        let allowance = Variable::Cw20Allowance {
+            token: token.clone(),
+            owner: Box::new(Variable::Raw("holder".to_string())),
+            spender: Box::new(Variable::Raw("spender".to_string())),
+        };
+        assert_eq!(
+            evaluate_variable(&deps.as_ref(), &condition_ctx, &allowance).unwrap(),
+            Some(Value::String("40".to_string()))
+        );
+        let symbol = Variable::Cw20TokenInfo {
+            token: token.clone(),
+            field: Cw20TokenInfoField::Symbol,
+        };
+        assert_eq!(
+            evaluate_variable(&deps.as_ref(), &condition_ctx, &symbol).unwrap(),
+            Some(Value::String("TKN".to_string()))
+        );
+
+        let missing = Variable::Cw20TokenInfo {
+            token: Box::new(Variable::Raw("missing".to_string())),
+            field: Cw20TokenInfoField::Decimals,
+        };
+        assert!(evaluate_variable(&deps.as_ref(), &condition_ctx, &missing).is_err());
+
+        // Holds more than 10% of the supply
+        let ctx = ExecuteContext::new(deps.as_mut(), mock_info("owner", &[]), env.clone());
+        add_variable(ctx, &balance, "holder_balance").unwrap();
+        let ctx = ExecuteContext::new(deps.as_mut(), mock_info("owner", &[]), env);
+        add_variable(
+            ctx,
+            &Variable::Cw20TokenInfo {
+                token,
+                field: Cw20TokenInfoField::TotalSupply,
+            },
+            "supply",
+        )
+        .unwrap();
+        let condition = Condition {
+            left: crate::types::ConditionWing::Expression(vec![
+                "holder_balance".to_string(),
+                "*".to_string(),
+                "10".to_string(),
+            ]),
+            right: crate::types::ConditionWing::Expression(vec!["supply".to_string()]),
+            compare: crate::types::ConditionCompare::Gt,
+            require_verified: None,
+        };
+        assert!(evaluate_condition(&deps.as_ref(), &condition_ctx, condition).unwrap());
+    }
+
+    #[test]
+    fn test_cw721_variables() {
+        let mut deps = mock_dependencies();
+        deps.querier.update_wasm(|query| match query {
+            WasmQuery::Smart { contract_addr, msg } if contract_addr == "collection" => {
+                let owner_of = |token_id: &str| match token_id {
+                    "3" => "other",
+                    "4" => "broken",
+                    _ => "holder",
+                };
+                let res = match from_binary(msg).unwrap() {
+                    Cw721QueryMsg::OwnerOf { token_id, .. } => to_binary(&OwnerOfResponse {
+                        owner: owner_of(&token_id).to_string(),
+                        approvals: if token_id == "3" {
+                            vec![Approval {
+                                spender: "approved".to_string(),
+                                expires: Expiration::Never {},
+                            }]
+                        } else {
+                            vec![]
+                        },
+                    }),
+                    Cw721QueryMsg::Tokens { owner, .. } => to_binary(&TokensResponse {
+                        tokens: ["1", "2", "3"]
+                            .into_iter()
+                            .filter(|token_id| owner_of(token_id) == owner)
+                            .map(String::from)
+                            .collect(),
+                    }),
+                    Cw721QueryMsg::AllOperators { owner, .. } if owner == "broken" => {
+                        return SystemResult::Ok(ContractResult::Err("Out of gas".to_string()))
+                    }
+                    Cw721QueryMsg::AllOperators { owner, .. } => to_binary(&OperatorsResponse {
+                        operators: if owner == "holder" {
+                            vec![Approval {
+                                spender: "operator".to_string(),
+                                expires: Expiration::Never {},
+                            }]
+                        } else {
+                            vec![]
+                        },
+                    }),
+                    _ => unimplemented!(),
+                };
+                SystemResult::Ok(ContractResult::Ok(res.unwrap()))
+            }
+            _ => SystemResult::Err(SystemError::NoSuchContract {
+                addr: "unknown".to_string(),
+            }),
+        });
+        let env = mock_env();
+        let execute_ctx = InwardExecuteCtx {
+            env: env.clone(),
+            msg: encode_binary(&"".to_string()).unwrap(),
+            funds: vec![],
+            sender: Addr::unchecked("holder"),
+            original_sender: Addr::unchecked("holder"),
+        };
+        let condition_ctx = create_condition_ctx(env.clone(), Some(execute_ctx));
+        let collection = Box::new(Variable::Raw("collection".to_string()));
+        let sender = Box::new(Variable::Reference("execute_ctx.sender".to_string()));
+
+        let owner = Variable::Cw721OwnerOf {
+            collection: collection.clone(),
+            token_id: Box::new(Variable::Raw("3".to_string())),
+        };
+        assert_eq!(
+            evaluate_variable(&deps.as_ref(), &condition_ctx, &owner).unwrap(),
+            Some(Value::String("other".to_string()))
+        );
+        let tokens = Variable::Cw721Tokens {
+            collection: collection.clone(),
+            owner: sender.clone(),
+        };
+        assert_eq!(
+            evaluate_variable(&deps.as_ref(), &condition_ctx, &tokens).unwrap(),
+            Some(Value::String("2".to_string()))
+        );
+
+        let is_approved = |token_id: &str, spender: Box<Variable>| {
+            let variable = Variable::Cw721IsApproved {
+                collection: collection.clone(),
+                token_id: Box::new(Variable::Raw(token_id.to_string())),
+                spender,
+            };
+            evaluate_variable(&deps.as_ref(), &condition_ctx, &variable).unwrap()
+        };
+        // Owner, token approval and operator
+        assert_eq!(is_approved("1", sender.clone()), Some(Value::Bool(true)));
+        assert_eq!(
+            is_approved("3", Box::new(Variable::Raw("approved".to_string()))),
+            Some(Value::Bool(true))
+        );
+        assert_eq!(
+            is_approved("2", Box::new(Variable::Raw("operator".to_string()))),
+            Some(Value::Bool(true))
+        );
+        assert_eq!(is_approved("3", sender.clone()), Some(Value::Bool(false)));
+        // A failing operator query fails the variable rather than reading as not approved
+        let variable = Variable::Cw721IsApproved {
+            collection: collection.clone(),
+            token_id: Box::new(Variable::Raw("4".to_string())),
+            spender: sender,
+        };
+        assert!(evaluate_variable(&deps.as_ref(), &condition_ctx, &variable).is_err());
+
+        // Sender holds at least 2 tokens of the collection
+        let ctx = ExecuteContext::new(deps.as_mut(), mock_info("owner", &[]), env);
+        add_variable(ctx, &tokens, "sender_tokens").unwrap();
+        let condition = Condition {
+            left: crate::types::ConditionWing::Expression(vec!["sender_tokens".to_string()]),
+            right: crate::types::ConditionWing::Number(2.into()),
+            compare: crate::types::ConditionCompare::Gte,
+            require_verified: None,
+        };
+        assert!(evaluate_condition(&deps.as_ref(), &condition_ctx, condition).unwrap());
+    }
+
+    #[test]
+    fn test_staking_variables() {
+        let block = mock_env().block;
+        let mut app = App::new(|router, api, storage| {
+            router
+                .staking
+                .setup(
+                    storage,
+                    StakingInfo {
+                        bonded_denom: "ustake".to_string(),
+                        unbonding_time: 60,
+                        apr: Decimal::percent(10),
+                    },
+                )
+                .unwrap();
+            for validator in ["validator1", "validator2"] {
+                router
+                    .staking
+                    .add_validator(
+                        api,
+                        storage,
+                        &block,
+                        Validator {
+                            address: validator.to_string(),
+                            commission: Decimal::percent(5),
+                            max_commission: Decimal::percent(10),
+                            max_change_rate: Decimal::percent(1),
+                        },
+                    )
+                    .unwrap();
+            }
+            router
+                .bank
+                .init_balance(
+                    storage,
+                    &Addr::unchecked("delegator"),
+                    coins(1000, "ustake"),
+                )
+                .unwrap();
+        });
+        for (validator, amount) in [("validator1", 300), ("validator2", 200)] {
+            app.execute(
+                Addr::unchecked("delegator"),
+                CosmosMsg::Staking(StakingMsg::Delegate {
+                    validator: validator.to_string(),
+                    amount: coin(amount, "ustake"),
+                }),
+            )
+            .unwrap();
+        }
+
+        let total = Variable::TotalDelegated {
+            delegator: Box::new(Variable::Raw("delegator".to_string())),
+        };
+        let mut storage = MockStorage::new();
+        VARIABLES
+            .save(&mut storage, "total_delegated", &total)
+            .unwrap();
+        let deps = Deps {
+            storage: &storage,
+            api: app.api(),
+            querier: app.wrap(),
+        };
+        let condition_ctx = create_condition_ctx(mock_env(), None);
+
+        let delegation = Variable::Delegation {
+            delegator: Box::new(Variable::Raw("delegator".to_string())),
+            validator: Box::new(Variable::Raw("validator1".to_string())),
+        };
+        assert_eq!(
+            evaluate_variable(&deps, &condition_ctx, &delegation).unwrap(),
+            Some(Value::String("300".to_string()))
+        );
+        let no_delegation = Variable::Delegation {
+            delegator: Box::new(Variable::Raw("other".to_string())),
+            validator: Box::new(Variable::Raw("validator1".to_string())),
+        };
+        assert_eq!(
+            evaluate_variable(&deps, &condition_ctx, &no_delegation).unwrap(),
+            Some(Value::String("0".to_string()))
+        );
+        assert_eq!(
+            evaluate_variable(&deps, &condition_ctx, &total).unwrap(),
+            Some(Value::String("500".to_string()))
+        );
+        assert_eq!(
+            evaluate_variable(&deps, &condition_ctx, &Variable::BondedDenom).unwrap(),
+            Some(Value::String("ustake".to_string()))
+        );
+        let is_active = |validator: &str| {
+            let variable = Variable::IsActiveValidator {
+                validator: Box::new(Variable::Raw(validator.to_string())),
+            };
+            evaluate_variable(&deps, &condition_ctx, &variable).unwrap()
+        };
+        assert_eq!(is_active("validator1"), Some(Value::Bool(true)));
+        assert_eq!(is_active("validator3"), Some(Value::Bool(false)));
+
+        // Has at least 400 staked
+        let condition = Condition {
+            left: crate::types::ConditionWing::Expression(vec!["total_delegated".to_string()]),
+            right: crate::types::ConditionWing::Number(400.into()),
+            compare: crate::types::ConditionCompare::Gte,
+            require_verified: None,
+        };
+        assert!(evaluate_condition(&deps, &condition_ctx, condition).unwrap());
+    }
+
+    #[test]
+    fn test_template_query() {
+        let mut deps = mock_dependencies();
+        deps.querier.update_wasm(|query| match query {
+            WasmQuery::Smart { contract_addr, msg } if contract_addr == "token" => {
+                let Cw20QueryMsg::Balance { address } = from_binary(msg).unwrap() else {
+                    unimplemented!()
+                };
+                let balance = match address.as_str() {
+                    "sender" => 70u128,
+                    "treasury" => 900,
+                    _ => 0,
+                };
+                SystemResult::Ok(ContractResult::Ok(
+                    to_binary(&BalanceResponse {
+                        balance: Uint128::new(balance),
+                    })
+                    .unwrap(),
+                ))
+            }
+            _ => SystemResult::Err(SystemError::NoSuchContract {
+                addr: "unknown".to_string(),
+            }),
+        });
+        let env = mock_env();
+        let execute_ctx = InwardExecuteCtx {
+            env: env.clone(),
+            msg: encode_binary(&"".to_string()).unwrap(),
+            funds: vec![],
+            sender: Addr::unchecked("sender"),
+            original_sender: Addr::unchecked("sender"),
+        };
+        let condition_ctx = create_condition_ctx(env.clone(), Some(execute_ctx));
+        let balance_of = |address: &str| {
+            Variable::Query(Box::new(ExternalQuery {
+                contract: Variable::Raw("token".to_string()),
+                query: ExternalQueryMsg::Template(ExternalQueryTemplateMsg {
+                    msg: Binary::from(
+                        format!(r#"{{"balance":{{"address":{address}}}}}"#).as_bytes(),
+                    ),
+                }),
+                result: Some("balance".to_string()),
+            }))
+        };
+
+        let sender_balance = balance_of(r#"{"$ref":"execute_ctx.sender"}"#);
+        assert_eq!(
+            evaluate_variable(&deps.as_ref(), &condition_ctx, &sender_balance).unwrap(),
+            Some(Value::String("70".to_string()))
+        );
+
+        // Placeholders can use other variables, which makes them dependencies
+        let treasury_balance = balance_of(r#"{"$var":"treasury"}"#);
+        let ctx = ExecuteContext::new(deps.as_mut(), mock_info("owner", &[]), env.clone());
+        let err = add_variable(ctx, &treasury_balance, "treasury_balance").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            ContractError::from(ConditionalError::UndefinedName {
+                name: "treasury".to_string()
+            })
+            .to_string()
+        );
+        let ctx = ExecuteContext::new(deps.as_mut(), mock_info("owner", &[]), env.clone());
+        add_variable(ctx, &Variable::Raw("treasury".to_string()), "treasury").unwrap();
+        let ctx = ExecuteContext::new(deps.as_mut(), mock_info("owner", &[]), env.clone());
+        add_variable(ctx, &treasury_balance, "treasury_balance").unwrap();
+        assert_eq!(
+            evaluate_variable(&deps.as_ref(), &condition_ctx, &treasury_balance).unwrap(),
+            Some(Value::String("900".to_string()))
+        );
+
+        let ctx = ExecuteContext::new(deps.as_mut(), mock_info("owner", &[]), env);
+        let err = add_variable(ctx, &balance_of(r#"{"$var":1}"#), "invalid").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            ContractError::from(ConditionalError::InvalidTemplate {
+                msg: "$var must be a string".to_string()
+            })
+            .to_string()
+        );
+    }
+
+    #[test]
+    fn test_storage_query() {
+        const CONFIG: Item<u64> = Item::new("config");
+        const BALANCES: Map<&Addr, Uint128> = Map::new("balances");
+        const SCORES: Map<(&str, i64), String> = Map::new("scores");
+        let mut storage = MockStorage::new();
+        CONFIG.save(&mut storage, &5).unwrap();
+        BALANCES
+            .save(&mut storage, &Addr::unchecked("sender"), &Uint128::new(70))
+            .unwrap();
+        SCORES
+            .save(&mut storage, ("alice", -5), &"low".to_string())
+            .unwrap();
+
+        let mut deps = mock_dependencies();
+        deps.querier.update_wasm(move |query| match query {
+            WasmQuery::Raw { contract_addr, key } if contract_addr == "other" => {
+                // Missing keys are returned as empty data
+                let data = storage.get(key).unwrap_or_default();
+                SystemResult::Ok(ContractResult::Ok(Binary::from(data)))
+            }
+            _ => SystemResult::Err(SystemError::NoSuchContract {
+                addr: "unknown".to_string(),
+            }),
+        });
+        let env = mock_env();
+        let execute_ctx = InwardExecuteCtx {
+            env: env.clone(),
+            msg: encode_binary(&"".to_string()).unwrap(),
+            funds: vec![],
+            sender: Addr::unchecked("sender"),
+            original_sender: Addr::unchecked("sender"),
+        };
+        let condition_ctx = create_condition_ctx(env, Some(execute_ctx));
+        let storage_query = |namespace: &str, keys: Vec<StorageKey>| {
+            Variable::Query(Box::new(ExternalQuery {
+                contract: Variable::Raw("other".to_string()),
+                query: ExternalQueryMsg::Storage(ExternalQueryStorageMsg {
+                    namespace: namespace.to_string(),
+                    keys,
+                    decode: None,
+                }),
+                result: None,
+            }))
+        };
+
+        let config = storage_query("config", vec![]);
+        assert_eq!(
+            evaluate_variable(&deps.as_ref(), &condition_ctx, &config).unwrap(),
+            Some(Value::U64(5))
+        );
+        let sender_balance = storage_query(
+            "balances",
+            vec![StorageKey {
+                key_type: StorageKeyType::Addr,
+                value: Variable::Reference("execute_ctx.sender".to_string()),
+            }],
+        );
+        assert_eq!(
+            evaluate_variable(&deps.as_ref(), &condition_ctx, &sender_balance).unwrap(),
+            Some(Value::String("70".to_string()))
+        );
+        let score = |value: &str| {
+            storage_query(
+                "scores",
+                vec![
+                    StorageKey {
+                        key_type: StorageKeyType::String,
+                        value: Variable::Raw("alice".to_string()),
+                    },
+                    StorageKey {
+                        key_type: StorageKeyType::I64,
+                        value: Variable::Raw(value.to_string()),
+                    },
+                ],
+            )
+        };
+        assert_eq!(
+            evaluate_variable(&deps.as_ref(), &condition_ctx, &score("-5")).unwrap(),
+            Some(Value::String("low".to_string()))
+        );
+        assert_eq!(
+            evaluate_variable(&deps.as_ref(), &condition_ctx, &score("5")).unwrap(),
+            None
+        );
+        let err = evaluate_variable(&deps.as_ref(), &condition_ctx, &score("five")).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            ConditionalError::InvalidStorageKey {
+                key_type: "I64".to_string(),
+                value: "five".to_string()
+            }
+            .to_string()
+        );
+    }
+
+    #[test]
+    fn test_raw_query_decode() {
+        let mut storage = MockStorage::new();
+        storage.set(b"counter", &42u64.to_be_bytes());
+        storage.set(b"total", &1_000_000u128.to_be_bytes());
+        storage.set(b"owner", b"owner_address");
+        storage.set(b"blob", &[0xde, 0xad]);
+
+        let mut deps = mock_dependencies();
+        deps.querier.update_wasm(move |query| match query {
+            WasmQuery::Raw { contract_addr, key } if contract_addr == "other" => {
+                let data = storage.get(key).unwrap_or_default();
+                SystemResult::Ok(ContractResult::Ok(Binary::from(data)))
+            }
+            _ => SystemResult::Err(SystemError::NoSuchContract {
+                addr: "unknown".to_string(),
+            }),
+        });
+        let condition_ctx = create_condition_ctx(mock_env(), None);
+        let raw_query = |key: &str, decode: RawDecode| {
+            let variable = Variable::Query(Box::new(ExternalQuery {
+                contract: Variable::Raw("other".to_string()),
+                query: ExternalQueryMsg::Raw(ExternalQueryRawMsg {
+                    key: key.to_string(),
+                    decode: Some(decode),
+                }),
+                result: None,
+            }));
+            evaluate_variable(&deps.as_ref(), &condition_ctx, &variable)
+        };
+
+        let string = |value: &str| Some(Value::String(value.to_string()));
+        assert_eq!(
+            raw_query("counter", RawDecode::U64Be).unwrap(),
+            string("42")
+        );
+        assert_eq!(
+            raw_query("total", RawDecode::U128Be).unwrap(),
+            string("1000000")
+        );
+        assert_eq!(
+            raw_query("owner", RawDecode::Utf8).unwrap(),
+            string("owner_address")
+        );
+        assert_eq!(raw_query("blob", RawDecode::Hex).unwrap(), string("dead"));
+        assert_eq!(
+            raw_query("blob", RawDecode::Base64).unwrap(),
+            string("3q0=")
+        );
+
+        let err = raw_query("blob", RawDecode::U64Be).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            ConditionalError::InvalidRawData {
+                decode: "U64Be".to_string()
+            }
+            .to_string()
+        );
+        assert!(raw_query("counter", RawDecode::Json).is_err());
+    }
+
+    #[test]
+    fn test_result_path() {
+        let mut deps = mock_dependencies();
+        deps.querier.update_wasm(|query| match query {
+            WasmQuery::Smart { contract_addr, .. } if contract_addr == "vault" => {
+                SystemResult::Ok(ContractResult::Ok(Binary::from(
+                    br#"{"balances":[{"denom":"uatom","amount":"5"},{"denom":"uosmo","amount":"7"}]}"#
+                        .as_slice(),
+                )))
+            }
+            _ => SystemResult::Err(SystemError::NoSuchContract {
+                addr: "unknown".to_string(),
+            }),
+        });
+        let env = mock_env();
+        let execute_ctx = InwardExecuteCtx {
+            env: env.clone(),
+            msg: encode_binary(&"".to_string()).unwrap(),
+            funds: vec![coin(100, "uatom"), coin(20, "uosmo"), coin(50, "uatom")],
+            sender: Addr::unchecked("sender"),
+            original_sender: Addr::unchecked("sender"),
+        };
+        let condition_ctx = create_condition_ctx(env.clone(), Some(execute_ctx));
+        let vault_query = |path: &str| {
+            Variable::Query(Box::new(ExternalQuery {
+                contract: Variable::Raw("vault".to_string()),
+                query: ExternalQueryMsg::Smart(ExternalQuerySmartMsg {
+                    msg: encode_binary(&"balances".to_string()).unwrap(),
+                }),
+                result: Some(path.to_string()),
+            }))
+        };
+
+        assert_eq!(
+            evaluate_variable(
+                &deps.as_ref(),
+                &condition_ctx,
+                &vault_query("balances[?denom=='uosmo'].amount.sum()")
+            )
+            .unwrap(),
+            Some(Value::String("7".to_string()))
+        );
+        assert_eq!(
+            evaluate_variable(
+                &deps.as_ref(),
+                &condition_ctx,
+                &vault_query("balances.length()")
+            )
+            .unwrap(),
+            Some(Value::U64(2))
+        );
+
+        let sent_atom =
+            Variable::Reference("execute_ctx.funds[?denom=='uatom'].amount.sum()".to_string());
+        assert_eq!(
+            evaluate_variable(&deps.as_ref(), &condition_ctx, &sent_atom).unwrap(),
+            Some(Value::String("150".to_string()))
+        );
+
+        let ctx = ExecuteContext::new(deps.as_mut(), mock_info("owner", &[]), env);
+        let err = add_variable(ctx, &vault_query("balances[?denom]"), "invalid").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            ContractError::from(ConditionalError::InvalidPath {
+                path: "balances[?denom]".to_string(),
+                msg: "Invalid filter".to_string()
+            })
+            .to_string()
+        );
+    }
+
+    #[test]
+    fn test_evaluation_cache() {
+        let mut deps = mock_dependencies();
+        let oracle_queries = Rc::new(Cell::new(0));
+        let counter = oracle_queries.clone();
+        deps.querier.update_wasm(move |query| match query {
+            WasmQuery::Smart { contract_addr, .. } if contract_addr == "oracle" => {
+                counter.set(counter.get() + 1);
+                SystemResult::Ok(ContractResult::Ok(to_binary(&"150".to_string()).unwrap()))
+            }
+            _ => SystemResult::Err(SystemError::NoSuchContract {
+                addr: "unknown".to_string(),
+            }),
+        });
+        let env = mock_env();
+        let price = Variable::Query(Box::new(ExternalQuery {
+            contract: Variable::Raw("oracle".to_string()),
+            query: ExternalQueryMsg::Smart(ExternalQuerySmartMsg {
+                msg: encode_binary(&"price".to_string()).unwrap(),
+            }),
+            result: None,
+        }));
+        let ctx = ExecuteContext::new(deps.as_mut(), mock_info("owner", &[]), env.clone());
+        add_variable(ctx, &price, "price").unwrap();
+        // Same query under another name
+        let ctx = ExecuteContext::new(deps.as_mut(), mock_info("owner", &[]), env.clone());
+        add_variable(ctx, &price, "oracle_price").unwrap();
+        let ctx = ExecuteContext::new(deps.as_mut(), mock_info("owner", &[]), env.clone());
+        add_variable(
+            ctx,
+            &Variable::Expression(vec!["price".to_string(), "*".to_string(), "2".to_string()]),
+            "double_price",
+        )
+        .unwrap();
+
+        // price * 2 > price + 100 and oracle_price == price
+        let condition = Condition {
+            left: crate::types::ConditionWing::Condition(Box::new(Condition {
+                left: crate::types::ConditionWing::Expression(vec!["double_price".to_string()]),
+                right: crate::types::ConditionWing::Expression(vec![
+                    "price".to_string(),
+                    "+".to_string(),
+                    "100".to_string(),
+                ]),
+                compare: crate::types::ConditionCompare::Gt,
+                require_verified: None,
+            })),
+            right: crate::types::ConditionWing::Condition(Box::new(Condition {
+                left: crate::types::ConditionWing::Expression(vec!["oracle_price".to_string()]),
+                right: crate::types::ConditionWing::Expression(vec!["price".to_string()]),
+                compare: crate::types::ConditionCompare::Eq,
+                require_verified: None,
+            })),
+            compare: crate::types::ConditionCompare::Eq,
+            require_verified: None,
+        };
+        let ctx = ExecuteContext::new(deps.as_mut(), mock_info("owner", &[]), env.clone());
+        add_condition(ctx, condition, Some("price_check".to_string())).unwrap();
+
+        let condition_ctx = create_condition_ctx(env, None);
+        let explain =
+            explain_stored_condition(&deps.as_ref(), &condition_ctx, Some("price_check")).unwrap();
+        assert_eq!(oracle_queries.get(), 1);
+        assert_eq!(
+            explain,
+            ExplainResponse {
+                result: true,
+                variables: vec![
+                    ("price".to_string(), Some("150".to_string())),
+                    ("double_price".to_string(), Some("300".to_string())),
+                    ("oracle_price".to_string(), Some("150".to_string())),
+                ],
+                external_queries: 1,
+                // price is read again twice, the oracle_price query once
+                cache_hits: 3,
+            }
+        );
+
+        // Bank and staking queries written inline are cached too
+        let balance = Variable::Balance {
+            address: Box::new(Variable::Raw("holder".to_string())),
+            denom: "uatom".to_string(),
+        };
+        let mut eval = Evaluation::new(deps.as_ref().storage).unwrap();
+        for _ in 0..2 {
+            assert_eq!(
+                evaluate_variable_with(&deps.as_ref(), &condition_ctx, &mut eval, &balance)
+                    .unwrap(),
+                Some(Value::String("0".to_string()))
+            );
+        }
+        assert_eq!(eval.external_queries, 1);
+        assert_eq!(eval.cache_hits, 1);
+    }
+
+    #[test]
+    fn test_limits() {
+        let mut deps = mock_dependencies();
+        deps.querier.update_wasm(|_| {
+            SystemResult::Ok(ContractResult::Ok(to_binary(&"1".to_string()).unwrap()))
+        });
+        let env = mock_env();
+        let limits = EvaluationLimits {
+            max_depth: 3,
+            max_external_queries: 1,
+            max_expression_tokens: 3,
+            max_steps: 100,
+        };
+        instantiate(
+            deps.as_mut(),
+            env.clone(),
+            mock_info("owner", &[]),
+            InstantiateMsg {
+                kernel_address: "kernel".to_string(),
+                owner: None,
+                variables: vec![],
+                condition: None,
+                conditions: vec![],
+                limits: Some(limits.clone()),
+                timelock: None,
+            },
+        )
+        .unwrap();
+        let res: EvaluationLimits =
+            from_binary(&query(deps.as_ref(), env.clone(), QueryMsg::Limits {}).unwrap()).unwrap();
+        assert_eq!(res, limits);
+        let condition_ctx = create_condition_ctx(env.clone(), None);
+
+        // Each variable resolving another one nests a level deeper
+        let nested = |depth: usize| {
+            (0..depth).fold(Variable::Raw("1".to_string()), |variable, _| {
+                Variable::WithDefault {
+                    variable: Box::new(variable),
+                    default: "0".to_string(),
+                }
+            })
+        };
+        evaluate_variable(&deps.as_ref(), &condition_ctx, &nested(2)).unwrap();
+        let err = evaluate_variable(&deps.as_ref(), &condition_ctx, &nested(3)).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            ConditionalError::LimitExceeded {
+                limit: "nested levels".to_string(),
+                max: 3
+            }
+            .to_string()
+        );
+
+        let err = evaluate_variable(
+            &deps.as_ref(),
+            &condition_ctx,
+            &Variable::Expression(vec![
+                "1".to_string(),
+                "+".to_string(),
+                "2".to_string(),
+                "+".to_string(),
+                "3".to_string(),
+            ]),
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            ConditionalError::LimitExceeded {
+                limit: "expression tokens".to_string(),
+                max: 3
+            }
+            .to_string()
+        );
+
+        // Going over a limit is not skipped by a coalesce
+        let query = |msg: &str| {
+            Variable::Query(Box::new(ExternalQuery {
+                contract: Variable::Raw("oracle".to_string()),
+                query: ExternalQueryMsg::Smart(ExternalQuerySmartMsg {
+                    msg: encode_binary(&msg.to_string()).unwrap(),
+                }),
+                result: None,
+            }))
+        };
+        let variable = Variable::Coalesce(vec![
+            Variable::Expression(vec!["1".to_string(), "+".to_string(), "1".to_string()]),
+            query("a"),
+        ]);
+        evaluate_variable(&deps.as_ref(), &condition_ctx, &variable).unwrap();
+        let ctx = ExecuteContext::new(deps.as_mut(), mock_info("owner", &[]), env.clone());
+        add_variable(ctx, &query("a"), "a").unwrap();
+        let ctx = ExecuteContext::new(deps.as_mut(), mock_info("owner", &[]), env.clone());
+        add_variable(ctx, &query("b"), "b").unwrap();
+        let err = evaluate_variable(
+            &deps.as_ref(),
+            &condition_ctx,
+            &Variable::Expression(vec!["a".to_string(), "+".to_string(), "b".to_string()]),
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            ConditionalError::LimitExceeded {
+                limit: "external queries".to_string(),
+                max: 1
+            }
+            .to_string()
+        );
+
+        let ctx = ExecuteContext::new(deps.as_mut(), mock_info("anyone", &[]), env.clone());
+        let err = set_limits(ctx, EvaluationLimits::default()).unwrap_err();
+        assert_eq!(err.to_string(), ContractError::Unauthorized {}.to_string());
+        let ctx = ExecuteContext::new(deps.as_mut(), mock_info("owner", &[]), env.clone());
+        let err = set_limits(
+            ctx,
+            EvaluationLimits {
+                max_steps: 0,
+                ..EvaluationLimits::default()
+            },
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            ContractError::from(ConditionalError::InvalidLimits {}).to_string()
+        );
+        let ctx = ExecuteContext::new(deps.as_mut(), mock_info("owner", &[]), env);
+        set_limits(ctx, EvaluationLimits::default()).unwrap();
+        assert_eq!(
+            LIMITS.load(deps.as_ref().storage).unwrap(),
+            EvaluationLimits::default()
+        );
+    }
+
+    #[test]
+    fn test_validate() {
+        let mut deps = mock_dependencies();
+        let env = mock_env();
+        let ctx = ExecuteContext::new(deps.as_mut(), mock_info("owner", &[]), env.clone());
+        add_variable(ctx, &Variable::Raw("1".to_string()), "price").unwrap();
+        let diagnostic = |path: &str, err: ConditionalError| Diagnostic {
+            path: path.to_string(),
+            message: err.to_string(),
+        };
+
+        let condition = Condition {
+            left: crate::types::ConditionWing::Expression(vec![
+                "price".to_string(),
+                "*".to_string(),
+            ]),
+            right: crate::types::ConditionWing::Condition(Box::new(Condition {
+                left: crate::types::ConditionWing::Expression(vec![
+                    "price".to_string(),
+                    "+".to_string(),
+                    "fee".to_string(),
+                ]),
+                right: crate::types::ConditionWing::String("abc".to_string()),
+                compare: crate::types::ConditionCompare::Gt,
+                require_verified: None,
+            })),
+            compare: crate::types::ConditionCompare::Eq,
+            require_verified: None,
+        };
+        let res: Vec<Diagnostic> = from_binary(
+            &query(
+                deps.as_ref(),
+                env.clone(),
+                QueryMsg::ValidateCondition { condition },
+            )
+            .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(
+            res,
+            vec![
+                diagnostic(
+                    "left",
+                    ConditionalError::InvalidExpression {
+                        msg: "Invalid expression".to_string()
+                    }
+                ),
+                diagnostic(
+                    "right.left",
+                    ConditionalError::UndefinedName {
+                        name: "fee".to_string()
+                    }
+                ),
+                diagnostic("right", ConditionalError::InvalidOperator {}),
+            ]
+        );
+
+        // Well formed conditions have no diagnostics
+        let condition = Condition {
+            left: crate::types::ConditionWing::Expression(vec!["price".to_string()]),
+            right: crate::types::ConditionWing::Number(1.into()),
+            compare: crate::types::ConditionCompare::Gte,
+            require_verified: None,
+        };
+        let res: Vec<Diagnostic> = from_binary(
+            &query(
+                deps.as_ref(),
+                env.clone(),
+                QueryMsg::ValidateCondition { condition },
+            )
+            .unwrap(),
+        )
+        .unwrap();
+        assert!(res.is_empty());
+
+        let variable = Variable::Coalesce(vec![
+            Variable::Balance {
+                address: Box::new(Variable::Raw("Holder".to_string())),
+                denom: "uatom".to_string(),
+            },
+            Variable::Reference("env..height".to_string()),
+        ]);
+        let res: Vec<Diagnostic> = from_binary(
+            &query(
+                deps.as_ref(),
+                env.clone(),
+                QueryMsg::ValidateVariable { variable },
+            )
+            .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(res.len(), 2);
+        assert_eq!(res[0].path, "0.address");
+        assert_eq!(
+            res[1],
+            diagnostic(
+                "1",
+                ConditionalError::InvalidPath {
+                    path: "env..height".to_string(),
+                    msg: "Empty path segment".to_string()
+                }
+            )
+        );
+
+        // Anything the validate queries report is rejected when stored
+        let condition = Condition {
+            left: crate::types::ConditionWing::Expression(vec!["price".to_string()]),
+            right: crate::types::ConditionWing::String("abc".to_string()),
+            compare: crate::types::ConditionCompare::Gt,
+            require_verified: None,
+        };
+        let ctx = ExecuteContext::new(deps.as_mut(), mock_info("owner", &[]), env.clone());
+        let err = add_condition(ctx, condition, None).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            ContractError::from(ConditionalError::InvalidOperator {}).to_string()
+        );
+        let variable = Variable::Expression(vec!["price".to_string(), "*".to_string()]);
+        let ctx = ExecuteContext::new(deps.as_mut(), mock_info("owner", &[]), env);
+        let err = add_variable(ctx, &variable, "total").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            ContractError::from(ConditionalError::InvalidExpression {
+                msg: "Invalid expression".to_string()
+            })
+            .to_string()
+        );
+    }
+
+    #[test]
+    fn test_overrides() {
+        let mut deps = mock_dependencies();
+        let env = mock_env();
+        let price = Variable::Query(Box::new(ExternalQuery {
+            contract: Variable::Raw("oracle".to_string()),
+            query: ExternalQueryMsg::Smart(ExternalQuerySmartMsg {
+                msg: encode_binary(&"price".to_string()).unwrap(),
+            }),
+            result: None,
+        }));
+        let ctx = ExecuteContext::new(deps.as_mut(), mock_info("owner", &[]), env.clone());
+        add_variable(ctx, &price, "price").unwrap();
+        let ctx = ExecuteContext::new(deps.as_mut(), mock_info("owner", &[]), env.clone());
+        add_variable(
+            ctx,
+            &Variable::Reference("query_ctx.env.block.height".to_string()),
+            "height",
+        )
+        .unwrap();
+        let condition = Condition {
+            left: crate::types::ConditionWing::Condition(Box::new(Condition {
+                left: crate::types::ConditionWing::Expression(vec!["price".to_string()]),
+                right: crate::types::ConditionWing::Number(100.into()),
+                compare: crate::types::ConditionCompare::Gt,
+                require_verified: None,
+            })),
+            right: crate::types::ConditionWing::Condition(Box::new(Condition {
+                left: crate::types::ConditionWing::Expression(vec!["height".to_string()]),
+                right: crate::types::ConditionWing::Number(1_000_000.into()),
+                compare: crate::types::ConditionCompare::Gte,
+                require_verified: None,
+            })),
+            compare: crate::types::ConditionCompare::Eq,
+            require_verified: None,
+        };
+
+        // There is no oracle to query without overrides
+        let msg = |overrides: Overrides| QueryMsg::EvaluateCondition {
+            condition: condition.clone(),
+            ctx: None,
+            overrides,
+        };
+        assert!(query(deps.as_ref(), env.clone(), msg(Overrides::new())).is_err());
+
+        let overrides = Overrides::from([
+            ("price".to_string(), Value::String("150".to_string())),
+            (
+                "query_ctx.env.block.height".to_string(),
+                Value::U64(1_000_000),
+            ),
+        ]);
+        let res = query(deps.as_ref(), env.clone(), msg(overrides.clone())).unwrap();
+        assert!(from_binary::<bool>(&res).unwrap());
+
+        let res = query(
+            deps.as_ref(),
+            env.clone(),
+            QueryMsg::EvaluateVariable {
+                name: "height".to_string(),
+                ctx: None,
+                overrides,
+            },
+        )
+        .unwrap();
+        assert_eq!(
+            from_binary::<Option<Value>>(&res).unwrap(),
+            Some(Value::U64(1_000_000))
+        );
+
+        // Overrides only apply to the query they are sent with
+        let res = query(
+            deps.as_ref(),
+            env,
+            QueryMsg::EvaluateVariable {
+                name: "height".to_string(),
+                ctx: None,
+                overrides: Overrides::new(),
+            },
+        )
+        .unwrap();
+        assert_ne!(
+            from_binary::<Option<Value>>(&res).unwrap(),
+            Some(Value::U64(1_000_000))
+        );
+
+        // A querier supplied ctx cannot be made to pass as verified
+        let verified = Condition {
+            left: crate::types::ConditionWing::Bool(true),
+            right: crate::types::ConditionWing::Bool(true),
+            compare: crate::types::ConditionCompare::Eq,
+            require_verified: Some(true),
+        };
+        let err = query(
+            deps.as_ref(),
+            mock_env(),
+            QueryMsg::EvaluateCondition {
+                condition: verified,
+                ctx: None,
+                overrides: Overrides::from([("verified".to_string(), Value::Bool(true))]),
+            },
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            ContractError::from(ConditionalError::InvalidOverride {
+                key: "verified".to_string()
+            })
+            .to_string()
+        );
+    }
+
+    #[test]
+    fn test_evaluate_batch() {
+        let mut deps = mock_dependencies();
+        let oracle_queries = Rc::new(Cell::new(0));
+        let counter = oracle_queries.clone();
+        deps.querier.update_wasm(move |_| {
+            counter.set(counter.get() + 1);
+            SystemResult::Ok(ContractResult::Ok(to_binary(&"150".to_string()).unwrap()))
+        });
+        let env = mock_env();
+        let price = Variable::Query(Box::new(ExternalQuery {
+            contract: Variable::Raw("oracle".to_string()),
+            query: ExternalQueryMsg::Smart(ExternalQuerySmartMsg {
+                msg: encode_binary(&"price".to_string()).unwrap(),
+            }),
+            result: None,
+        }));
+        let ctx = ExecuteContext::new(deps.as_mut(), mock_info("owner", &[]), env.clone());
+        add_variable(ctx, &price, "price").unwrap();
+
+        let items = vec![
+            BatchItem::Variable {
+                name: "price".to_string(),
+            },
+            BatchItem::CustomCondition {
+                condition: Condition {
+                    left: crate::types::ConditionWing::Expression(vec!["price".to_string()]),
+                    right: crate::types::ConditionWing::Number(100.into()),
+                    compare: crate::types::ConditionCompare::Gt,
+                    require_verified: None,
+                },
+            },
+            BatchItem::Condition {
+                name: Some("missing".to_string()),
+            },
+            BatchItem::CustomVariable { variable: price },
+        ];
+        let res: Vec<BatchItemResult> = from_binary(
+            &query(
+                deps.as_ref(),
+                env,
+                QueryMsg::EvaluateBatch { items, ctx: None },
+            )
+            .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(res[0], BatchItemResult::Variable(Some("150".to_string())));
+        assert_eq!(res[1], BatchItemResult::Condition(true));
+        // A missing condition only fails its own item
+        assert!(matches!(res[2], BatchItemResult::Error(_)));
+        assert_eq!(res[3], BatchItemResult::Variable(Some("150".to_string())));
+        // Every item reads the price from the same query
+        assert_eq!(oracle_queries.get(), 1);
+    }
+
+    #[test]
+    fn test_condition_history() {
+        let mut deps = mock_dependencies();
+        let mut env = mock_env();
+        let condition = |height: i128| Condition {
+            left: crate::types::ConditionWing::Expression(vec!["height".to_string()]),
+            right: crate::types::ConditionWing::Number(height.into()),
+            compare: crate::types::ConditionCompare::Gt,
+            require_verified: None,
+        };
+        instantiate(
+            deps.as_mut(),
+            env.clone(),
+            mock_info("owner", &[]),
+            InstantiateMsg {
+                kernel_address: "kernel".to_string(),
+                owner: None,
+                variables: vec![(
+                    "height".to_string(),
+                    Variable::Reference("query_ctx.env.block.height".to_string()),
+                )],
+                condition: Some(condition(0)),
+                conditions: vec![],
+                limits: None,
+                timelock: None,
+            },
+        )
+        .unwrap();
+
+        env.block.height += 1;
+        let ctx = ExecuteContext::new(deps.as_mut(), mock_info("admin", &[]), env.clone());
+        let res = add_condition(ctx, condition(env.block.height as i128 + 100), None).unwrap();
+        assert!(res
+            .attributes
+            .iter()
+            .any(|attr| attr.key == "version" && attr.value == "2"));
+
+        let res: Vec<(u32, ConditionRecord)> = from_binary(
+            &query(
+                deps.as_ref(),
+                env.clone(),
+                QueryMsg::ConditionHistory {
+                    name: None,
+                    start_after: None,
+                    limit: None,
+                },
+            )
+            .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(
+            res,
+            vec![
+                (
+                    1,
+                    ConditionRecord {
+                        condition: condition(0),
+                        author: Addr::unchecked("owner"),
+                        height: env.block.height - 1,
+                        time: env.block.time,
+                    }
+                ),
+                (
+                    2,
+                    ConditionRecord {
+                        condition: condition(env.block.height as i128 + 100),
+                        author: Addr::unchecked("admin"),
+                        height: env.block.height,
+                        time: env.block.time,
+                    }
+                ),
+            ]
+        );
+
+        // The first version still holds, the current one does not
+        for (version, expected) in [(1, true), (2, false)] {
+            let res = query(
+                deps.as_ref(),
+                env.clone(),
+                QueryMsg::EvaluateAt {
+                    name: None,
+                    version,
+                    ctx: None,
+                },
+            )
+            .unwrap();
+            assert_eq!(from_binary::<bool>(&res).unwrap(), expected);
+        }
+        assert!(query(
+            deps.as_ref(),
+            env,
+            QueryMsg::EvaluateAt {
+                name: None,
+                version: 3,
+                ctx: None,
+            },
+        )
+        .is_err());
+
+        // Conditions changed through AMP are authored by the origin of the packet
+        let mut ctx = ExecuteContext::new(deps.as_mut(), mock_info("kernel", &[]), env.clone());
+        ctx.amp_ctx = Some(AMPPkt::new("origin", "kernel", vec![]));
+        add_condition(ctx, condition(0), None).unwrap();
+        let res: Vec<(u32, ConditionRecord)> = from_binary(
+            &query(
+                deps.as_ref(),
+                env,
+                QueryMsg::ConditionHistory {
+                    name: None,
+                    start_after: Some(2),
+                    limit: None,
+                },
+            )
+            .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(res[0].1.author, Addr::unchecked("origin"));
+    }
+
+    #[test]
+    fn test_timelock() {
+        let mut deps = mock_dependencies();
+        let mut env = mock_env();
+        let condition = |value: i128| Condition {
+            left: crate::types::ConditionWing::Number(value.into()),
+            right: crate::types::ConditionWing::Number(0.into()),
+            compare: crate::types::ConditionCompare::Gt,
+            require_verified: None,
+        };
+        instantiate(
+            deps.as_mut(),
+            env.clone(),
+            mock_info("owner", &[]),
+            InstantiateMsg {
+                kernel_address: "kernel".to_string(),
+                owner: None,
+                variables: vec![
+                    ("base".to_string(), Variable::Raw("1".to_string())),
+                    (
+                        "value".to_string(),
+                        Variable::Expression(vec![
+                            "base".to_string(),
+                            "+".to_string(),
+                            "1".to_string(),
+                        ]),
+                    ),
+                    ("unused".to_string(), Variable::Raw("1".to_string())),
+                ],
+                condition: Some(condition(1)),
+                conditions: vec![
+                    (
+                        "uses_value".to_string(),
+                        Condition {
+                            left: crate::types::ConditionWing::Expression(
+                                vec!["value".to_string()],
+                            ),
+                            right: crate::types::ConditionWing::Number(0.into()),
+                            compare: crate::types::ConditionCompare::Gt,
+                            require_verified: None,
+                        },
+                    ),
+                    (
+                        "is_admin".to_string(),
+                        Condition {
+                            left: crate::types::ConditionWing::Expression(
+                                vec!["admin".to_string()],
+                            ),
+                            right: crate::types::ConditionWing::String("admin".to_string()),
+                            compare: crate::types::ConditionCompare::Eq,
+                            require_verified: None,
+                        },
+                    ),
+                ],
+                limits: None,
+                timelock: Some(100),
+            },
+        )
+        .unwrap();
+
+        // Variables read by a condition, even through other variables, are timelocked too,
+        // as are literals that a variable of the same name would replace
+        for name in ["base", "value", "admin"] {
+            let ctx = ExecuteContext::new(deps.as_mut(), mock_info("owner", &[]), env.clone());
+            let err = add_variable(ctx, &Variable::Raw("0".to_string()), name).unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                ContractError::from(ConditionalError::TimelockedVariable {
+                    name: name.to_string()
+                })
+                .to_string()
+            );
+        }
+        let ctx = ExecuteContext::new(deps.as_mut(), mock_info("owner", &[]), env.clone());
+        add_variable(ctx, &Variable::Raw("0".to_string()), "unused").unwrap();
+
+        let ctx = ExecuteContext::new(deps.as_mut(), mock_info("owner", &[]), env.clone());
+        let err = add_condition(ctx, condition(0), None).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            ContractError::from(ConditionalError::TimelockActive {}).to_string()
+        );
+        let ctx = ExecuteContext::new(deps.as_mut(), mock_info("owner", &[]), env.clone());
+        let err = set_timelock(ctx, 10).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            ContractError::from(ConditionalError::TimelockDecrease {}).to_string()
+        );
+
+        let ctx = ExecuteContext::new(deps.as_mut(), mock_info("admin", &[]), env.clone());
+        propose_condition(ctx, condition(0), None).unwrap();
+        let effective = env.block.time.plus_seconds(100);
+        let status: ConditionStatus = from_binary(
+            &query(
+                deps.as_ref(),
+                env.clone(),
+                QueryMsg::ConditionStatus { name: None },
+            )
+            .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(status.active, Some(condition(1)));
+        assert_eq!(
+            status.pending,
+            Some(PendingCondition {
+                condition: condition(0),
+                proposer: Addr::unchecked("admin"),
+                effective,
+            })
+        );
+        // Only the proposer or the owner can replace a pending proposal
+        let ctx = ExecuteContext::new(deps.as_mut(), mock_info("anyone", &[]), env.clone());
+        let err = propose_condition(ctx, condition(2), None).unwrap_err();
+        assert_eq!(err.to_string(), ContractError::Unauthorized {}.to_string());
+        // Names read by pending conditions are timelocked as well
+        let pending = Condition {
+            left: crate::types::ConditionWing::Expression(vec!["threshold".to_string()]),
+            right: crate::types::ConditionWing::String("threshold".to_string()),
+            compare: crate::types::ConditionCompare::Eq,
+            require_verified: None,
+        };
+        let ctx = ExecuteContext::new(deps.as_mut(), mock_info("admin", &[]), env.clone());
+        propose_condition(ctx, pending, Some("pending".to_string())).unwrap();
+        let ctx = ExecuteContext::new(deps.as_mut(), mock_info("owner", &[]), env.clone());
+        let err = add_variable(ctx, &Variable::Raw("0".to_string()), "threshold").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            ContractError::from(ConditionalError::TimelockedVariable {
+                name: "threshold".to_string()
+            })
+            .to_string()
+        );
+
+        let ctx = ExecuteContext::new(deps.as_mut(), mock_info("anyone", &[]), env.clone());
+        let err = apply_condition(ctx, None).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            ContractError::from(ConditionalError::ProposalNotReady { effective }).to_string()
+        );
+        let ctx = ExecuteContext::new(deps.as_mut(), mock_info("anyone", &[]), env.clone());
+        let err = cancel_proposal(ctx, None).unwrap_err();
+        assert_eq!(err.to_string(), ContractError::Unauthorized {}.to_string());
+
+        env.block.time = effective;
+        let ctx = ExecuteContext::new(deps.as_mut(), mock_info("anyone", &[]), env.clone());
+        apply_condition(ctx, None).unwrap();
+        assert_eq!(CONDITION.load(deps.as_ref().storage).unwrap(), condition(0));
+        let ctx = ExecuteContext::new(deps.as_mut(), mock_info("anyone", &[]), env.clone());
+        let err = apply_condition(ctx, None).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            ContractError::from(ConditionalError::NoPendingCondition {}).to_string()
+        );
+
+        // The owner can withdraw proposals of others
+        let ctx = ExecuteContext::new(deps.as_mut(), mock_info("admin", &[]), env.clone());
+        propose_condition(ctx, condition(1), Some("named".to_string())).unwrap();
+        let ctx = ExecuteContext::new(deps.as_mut(), mock_info("owner", &[]), env.clone());
+        cancel_proposal(ctx, Some("named".to_string())).unwrap();
+        let status: ConditionStatus = from_binary(
+            &query(
+                deps.as_ref(),
+                env,
+                QueryMsg::ConditionStatus {
+                    name: Some("named".to_string()),
+                },
+            )
+            .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(
+            status,
+            ConditionStatus {
+                active: None,
+                pending: None
+            }
+        );
+    }
+
+    #[test]
+    fn test_freeze() {
+        let mut deps = mock_dependencies();
+        let env = mock_env();
+        let condition = Condition {
+            left: crate::types::ConditionWing::Expression(vec!["price".to_string()]),
+            right: crate::types::ConditionWing::Number(0.into()),
+            compare: crate::types::ConditionCompare::Gt,
+            require_verified: None,
+        };
+        instantiate(
+            deps.as_mut(),
+            env.clone(),
+            mock_info("owner", &[]),
+            InstantiateMsg {
+                kernel_address: "kernel".to_string(),
+                owner: None,
+                variables: vec![("price".to_string(), Variable::Raw("1".to_string()))],
+                condition: Some(condition.clone()),
+                conditions: vec![],
+                limits: None,
+                timelock: None,
+            },
+        )
+        .unwrap();
+        let frozen_err = |name: &str| {
+            ContractError::from(ConditionalError::Frozen {
+                name: name.to_string(),
+            })
+            .to_string()
+        };
+
+        let ctx = ExecuteContext::new(deps.as_mut(), mock_info("anyone", &[]), env.clone());
+        let err = freeze(ctx, Some("price".to_string())).unwrap_err();
+        assert_eq!(err.to_string(), ContractError::Unauthorized {}.to_string());
+        let ctx = ExecuteContext::new(deps.as_mut(), mock_info("owner", &[]), env.clone());
+        freeze(ctx, Some("price".to_string())).unwrap();
+
+        // Frozen names cannot change, even for the owner
+        let ctx = ExecuteContext::new(deps.as_mut(), mock_info("owner", &[]), env.clone());
+        let err = add_variable(ctx, &Variable::Raw("2".to_string()), "price").unwrap_err();
+        assert_eq!(err.to_string(), frozen_err("price"));
+        let ctx = ExecuteContext::new(deps.as_mut(), mock_info("owner", &[]), env.clone());
+        let err = propose_condition(ctx, condition.clone(), Some("price".to_string())).unwrap_err();
+        assert_eq!(err.to_string(), frozen_err("price"));
+        let ctx = ExecuteContext::new(deps.as_mut(), mock_info("owner", &[]), env.clone());
+        add_variable(ctx, &Variable::Raw("2".to_string()), "fee").unwrap();
+        let ctx = ExecuteContext::new(deps.as_mut(), mock_info("owner", &[]), env.clone());
+        add_condition(ctx, condition.clone(), None).unwrap();
+
+        // Freezing a condition freezes the variables it reads, directly or not
+        let total = Variable::Expression(vec!["fee".to_string(), "+".to_string(), "1".to_string()]);
+        let ctx = ExecuteContext::new(deps.as_mut(), mock_info("owner", &[]), env.clone());
+        add_variable(ctx, &total, "total").unwrap();
+        let reads_total = Condition {
+            left: crate::types::ConditionWing::Expression(vec!["total".to_string()]),
+            right: crate::types::ConditionWing::Number(0.into()),
+            compare: crate::types::ConditionCompare::Gt,
+            require_verified: None,
+        };
+        let ctx = ExecuteContext::new(deps.as_mut(), mock_info("owner", &[]), env.clone());
+        add_condition(ctx, reads_total, None).unwrap();
+        let ctx = ExecuteContext::new(deps.as_mut(), mock_info("owner", &[]), env.clone());
+        freeze(ctx, None).unwrap();
+        let ctx = ExecuteContext::new(deps.as_mut(), mock_info("owner", &[]), env.clone());
+        let err = add_condition(ctx, condition.clone(), None).unwrap_err();
+        assert_eq!(err.to_string(), frozen_err("Default condition"));
+        for name in ["fee", "total"] {
+            let ctx = ExecuteContext::new(deps.as_mut(), mock_info("owner", &[]), env.clone());
+            let err = add_variable(ctx, &Variable::Raw("3".to_string()), name).unwrap_err();
+            assert_eq!(err.to_string(), frozen_err(name));
+        }
+
+        // Literals are frozen too, so no variable can be defined in their place
+        let is_admin = Condition {
+            left: crate::types::ConditionWing::Expression(vec!["admin".to_string()]),
+            right: crate::types::ConditionWing::String("admin".to_string()),
+            compare: crate::types::ConditionCompare::Eq,
+            require_verified: None,
+        };
+        let ctx = ExecuteContext::new(deps.as_mut(), mock_info("owner", &[]), env.clone());
+        add_condition(ctx, is_admin, Some("is_admin".to_string())).unwrap();
+        let ctx = ExecuteContext::new(deps.as_mut(), mock_info("owner", &[]), env.clone());
+        freeze(ctx, Some("is_admin".to_string())).unwrap();
+        let ctx = ExecuteContext::new(deps.as_mut(), mock_info("owner", &[]), env.clone());
+        let err = add_variable(ctx, &Variable::Raw("admin".to_string()), "admin").unwrap_err();
+        assert_eq!(err.to_string(), frozen_err("admin"));
+
+        let ctx = ExecuteContext::new(deps.as_mut(), mock_info("owner", &[]), env.clone());
+        freeze_all(ctx).unwrap();
+        let ctx = ExecuteContext::new(deps.as_mut(), mock_info("owner", &[]), env.clone());
+        let err = add_condition(ctx, condition, None).unwrap_err();
+        assert_eq!(err.to_string(), frozen_err("Default condition"));
+        let ctx = ExecuteContext::new(deps.as_mut(), mock_info("owner", &[]), env.clone());
+        let err = add_variable(ctx, &Variable::Raw("3".to_string()), "new").unwrap_err();
+        assert_eq!(err.to_string(), frozen_err("new"));
+
+        let res = query(
+            deps.as_ref(),
+            env.clone(),
+            QueryMsg::IsFrozen { name: None },
+        )
+        .unwrap();
+        assert!(from_binary::<bool>(&res).unwrap());
+        let res = query(
+            deps.as_ref(),
+            env,
+            QueryMsg::Frozen {
+                start_after: None,
+                limit: None,
+            },
+        )
+        .unwrap();
+        assert_eq!(
+            from_binary::<FrozenResponse>(&res).unwrap(),
+            FrozenResponse {
+                all: true,
+                names: vec![
+                    "".to_string(),
+                    "admin".to_string(),
+                    "fee".to_string(),
+                    "is_admin".to_string(),
+                    "price".to_string(),
+                    "total".to_string()
+                ],
+            }
+        );
+    }
+
+    #[test]
+    fn test_set_proxy() {
+        let mut deps = mock_dependencies();
+        let env = mock_env();
+        instantiate(
+            deps.as_mut(),
+            env.clone(),
+            mock_info("owner", &[]),
+            InstantiateMsg {
+                kernel_address: "kernel".to_string(),
+                owner: None,
+                variables: vec![],
+                condition: None,
+                conditions: vec![],
+                limits: None,
+                timelock: None,
+            },
+        )
+        .unwrap();
+
+        let ctx = ExecuteContext::new(deps.as_mut(), mock_info("anyone", &[]), env.clone());
+        let err = set_proxy(ctx, "attacker".to_string(), None).unwrap_err();
+        assert_eq!(err.to_string(), ContractError::Unauthorized {}.to_string());
+        assert!(PROXY.may_load(deps.as_ref().storage).unwrap().is_none());
+
+        let ctx = ExecuteContext::new(deps.as_mut(), mock_info("owner", &[]), env);
+        set_proxy(ctx, "target".to_string(), None).unwrap();
+        assert_eq!(
+            PROXY.load(deps.as_ref().storage).unwrap(),
+            ProxyConfig {
+                target: Addr::unchecked("target"),
+                allowed_msgs: None,
+            }
+        );
+    }
+
+    #[test]
+    fn test_list_fireable_limits() {
+        let mut deps = mock_dependencies();
+        deps.querier.update_wasm(|_| {
+            SystemResult::Ok(ContractResult::Ok(to_binary(&"150".to_string()).unwrap()))
+        });
+        let env = mock_env();
+        let query = |msg: &str| {
+            Variable::Query(Box::new(ExternalQuery {
+                contract: Variable::Raw("oracle".to_string()),
+                query: ExternalQueryMsg::Smart(ExternalQuerySmartMsg {
+                    msg: encode_binary(&msg.to_string()).unwrap(),
+                }),
+                result: None,
+            }))
+        };
+        let condition = |name: &str| Condition {
+            left: crate::types::ConditionWing::Expression(vec![name.to_string()]),
+            right: crate::types::ConditionWing::Number(100.into()),
+            compare: crate::types::ConditionCompare::Gt,
+            require_verified: None,
+        };
+        instantiate(
+            deps.as_mut(),
+            env.clone(),
+            mock_info("owner", &[]),
+            InstantiateMsg {
+                kernel_address: "kernel".to_string(),
+                owner: None,
+                variables: vec![
+                    ("first".to_string(), query("first")),
+                    ("second".to_string(), query("second")),
+                ],
+                condition: None,
+                conditions: vec![
+                    ("first".to_string(), condition("first")),
+                    ("second".to_string(), condition("second")),
+                ],
+                limits: Some(EvaluationLimits {
+                    max_external_queries: 1,
+                    ..EvaluationLimits::default()
+                }),
+                timelock: None,
+            },
+        )
+        .unwrap();
+        for name in ["first", "second"] {
+            let ctx = ExecuteContext::new(deps.as_mut(), mock_info("owner", &[]), env.clone());
+            add_trigger(
+                ctx,
+                Trigger {
+                    condition_name: name.to_string(),
+                    msgs: vec![],
+                    reward: vec![],
+                    one_shot: false,
+                },
+            )
+            .unwrap();
+        }
+
+        // Each trigger makes one query, which the limit allows per trigger
+        let fireable = list_fireable(&deps.as_ref(), env.clone(), None, None).unwrap();
+        assert_eq!(fireable.trigger_ids, vec![0, 1]);
+
+        // Only `limit` triggers are scanned, callers continue from the last one
+        let fireable = list_fireable(&deps.as_ref(), env.clone(), None, Some(1)).unwrap();
+        assert_eq!(
+            fireable,
+            FireableResponse {
+                trigger_ids: vec![0],
+                last_scanned: Some(0),
+            }
+        );
+        let fireable = list_fireable(&deps.as_ref(), env.clone(), Some(0), Some(1)).unwrap();
+        assert_eq!(fireable.trigger_ids, vec![1]);
+        let fireable = list_fireable(&deps.as_ref(), env, Some(1), Some(1)).unwrap();
+        assert_eq!(
+            fireable,
+            FireableResponse {
+                trigger_ids: vec![],
+                last_scanned: None,
+            }
+        );
+    }
+
+    #[test]
+    fn test_evaluate_batch_limits() {
+        let mut deps = mock_dependencies();
+        deps.querier.update_wasm(|_| {
+            SystemResult::Ok(ContractResult::Ok(to_binary(&"150".to_string()).unwrap()))
+        });
+        let env = mock_env();
+        LIMITS
+            .save(
+                deps.as_mut().storage,
+                &EvaluationLimits {
+                    max_depth: 2,
+                    max_external_queries: 1,
+                    ..EvaluationLimits::default()
+                },
+            )
+            .unwrap();
+        let query_item = |msg: &str| BatchItem::CustomVariable {
+            variable: Variable::Query(Box::new(ExternalQuery {
+                contract: Variable::Raw("oracle".to_string()),
+                query: ExternalQueryMsg::Smart(ExternalQuerySmartMsg {
+                    msg: encode_binary(&msg.to_string()).unwrap(),
+                }),
+                result: None,
+            })),
+        };
+        let nested = BatchItem::CustomVariable {
+            variable: Variable::WithDefault {
+                variable: Box::new(Variable::WithDefault {
+                    variable: Box::new(Variable::Raw("1".to_string())),
+                    default: "0".to_string(),
+                }),
+                default: "0".to_string(),
+            },
+        };
+
+        // A failing item does not use up the limits of the items after it
+        let items = vec![nested, query_item("a"), query_item("b"), query_item("c")];
+        let res: Vec<BatchItemResult> = from_binary(
+            &query(
+                deps.as_ref(),
+                env,
+                QueryMsg::EvaluateBatch { items, ctx: None },
+            )
+            .unwrap(),
+        )
+        .unwrap();
+        assert!(matches!(res[0], BatchItemResult::Error(_)));
+        for result in &res[1..] {
+            assert_eq!(*result, BatchItemResult::Variable(Some("150".to_string())));
+        }
+    }
 }
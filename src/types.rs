@@ -1,6 +1,26 @@
+use std::collections::BTreeMap;
+
 use crate::packages::eval::eval::Tokens;
 use cosmwasm_schema::cw_serde;
-use cosmwasm_std::{Addr, Binary, Coin, Env, Int128};
+use cosmwasm_std::{Addr, Binary, Coin, CosmosMsg, Env, Int128, Timestamp};
+use cw_utils::Expiration;
+use schemars::{
+    gen::SchemaGenerator,
+    schema::{InstanceType, Schema, SchemaObject},
+};
+use serde_cw_value::Value;
+
+/// Values replacing named variables, or set in the ctx at their path for any other key
+pub type Overrides = BTreeMap<String, Value>;
+
+/// Override values are arbitrary json, which has no schema of its own
+pub fn overrides_schema(_: &mut SchemaGenerator) -> Schema {
+    SchemaObject {
+        instance_type: Some(InstanceType::Object.into()),
+        ..Default::default()
+    }
+    .into()
+}
 
 #[cw_serde]
 pub struct InwardExecuteCtx {
@@ -19,11 +39,56 @@ pub struct CurrentQueryCtx {
 pub struct ConditionCtx {
     pub execute_ctx: Option<InwardExecuteCtx>,
     pub query_ctx: CurrentQueryCtx,
+    /// True only when `execute_ctx` was built by the contract during execution,
+    /// never when it was supplied by a querier
+    pub verified: bool,
 }
 
 #[cw_serde]
 pub struct ExternalQueryRawMsg {
     pub key: String,
+    /// How the stored bytes are read, json when not set
+    pub decode: Option<RawDecode>,
+}
+
+#[cw_serde]
+pub enum RawDecode {
+    Json,
+    Utf8,
+    /// Big endian unsigned integers as written by e.g. `u64::to_be_bytes`
+    U64Be,
+    U128Be,
+    Base64,
+    Hex,
+}
+
+/// Raw query of an `Item` (no keys) or a `Map` entry of another contract, with the key
+/// encoded the same way cw-storage-plus encodes it
+#[cw_serde]
+pub struct ExternalQueryStorageMsg {
+    pub namespace: String,
+    pub keys: Vec<StorageKey>,
+    /// How the stored bytes are read, json when not set
+    pub decode: Option<RawDecode>,
+}
+
+#[cw_serde]
+pub struct StorageKey {
+    pub key_type: StorageKeyType,
+    pub value: Variable,
+}
+
+#[cw_serde]
+pub enum StorageKeyType {
+    String,
+    Addr,
+    U8,
+    U16,
+    U32,
+    U64,
+    U128,
+    I32,
+    I64,
 }
 
 #[cw_serde]
@@ -31,10 +96,19 @@ pub struct ExternalQuerySmartMsg {
     pub msg: Binary,
 }
 
+/// Json smart query message where `{"$var": name}` objects are replaced with the value
+/// of a stored variable and `{"$ref": path}` objects with the value at that ctx path
+#[cw_serde]
+pub struct ExternalQueryTemplateMsg {
+    pub msg: Binary,
+}
+
 #[cw_serde]
 pub enum ExternalQueryMsg {
     Raw(ExternalQueryRawMsg),
+    Storage(ExternalQueryStorageMsg),
     Smart(ExternalQuerySmartMsg),
+    Template(ExternalQueryTemplateMsg),
 }
 
 #[cw_serde]
@@ -49,6 +123,82 @@ pub enum Variable {
     Raw(String),
     Reference(String),
     Query(Box<ExternalQuery>),
+    /// Derived from other named variables, e.g. `collateral * price / 1000000`
+    Expression(Tokens),
+    /// The first variable that resolves, failing ones are skipped
+    Coalesce(Vec<Variable>),
+    /// Falls back to `default` when the variable does not resolve
+    WithDefault {
+        variable: Box<Variable>,
+        default: String,
+    },
+    /// Native balance of `denom` held by `address`
+    Balance {
+        address: Box<Variable>,
+        denom: String,
+    },
+    /// All native balances held by `address`
+    AllBalances {
+        address: Box<Variable>,
+    },
+    /// Total supply of a native denom
+    Supply {
+        denom: String,
+    },
+    /// CW20 balance of `address`
+    Cw20Balance {
+        token: Box<Variable>,
+        address: Box<Variable>,
+    },
+    /// Amount `spender` is allowed to spend from `owner`
+    Cw20Allowance {
+        token: Box<Variable>,
+        owner: Box<Variable>,
+        spender: Box<Variable>,
+    },
+    Cw20TokenInfo {
+        token: Box<Variable>,
+        field: Cw20TokenInfoField,
+    },
+    /// Current owner of `token_id` in `collection`
+    Cw721OwnerOf {
+        collection: Box<Variable>,
+        token_id: Box<Variable>,
+    },
+    /// Number of tokens `owner` holds in `collection`
+    Cw721Tokens {
+        collection: Box<Variable>,
+        owner: Box<Variable>,
+    },
+    /// Whether `spender` can transfer `token_id`, either as its owner, through an
+    /// approval on the token or as an operator of the owner
+    Cw721IsApproved {
+        collection: Box<Variable>,
+        token_id: Box<Variable>,
+        spender: Box<Variable>,
+    },
+    /// Amount `delegator` has staked with `validator`
+    Delegation {
+        delegator: Box<Variable>,
+        validator: Box<Variable>,
+    },
+    /// Amount `delegator` has staked across all validators
+    TotalDelegated {
+        delegator: Box<Variable>,
+    },
+    BondedDenom,
+    /// Whether `validator` is in the active validator set
+    IsActiveValidator {
+        validator: Box<Variable>,
+    },
+}
+
+#[cw_serde]
+pub enum Cw20TokenInfoField {
+    Name,
+    Symbol,
+    Decimals,
+    TotalSupply,
 }
 
 #[cw_serde]
@@ -75,4 +225,102 @@ pub struct Condition {
     pub left: ConditionWing,
     pub right: ConditionWing,
     pub compare: ConditionCompare,
+    /// Fail the condition unless it is evaluated against a verified ctx
+    pub require_verified: Option<bool>,
+}
+
+/// A version of a stored condition and who set it when
+#[cw_serde]
+pub struct ConditionRecord {
+    pub condition: Condition,
+    pub author: Addr,
+    pub height: u64,
+    pub time: Timestamp,
+}
+
+/// A condition change waiting for the timelock to pass
+#[cw_serde]
+pub struct PendingCondition {
+    pub condition: Condition,
+    pub proposer: Addr,
+    /// Time from which the change can be applied
+    pub effective: Timestamp,
+}
+
+/// Bounds on the work a single evaluation may do
+#[cw_serde]
+pub struct EvaluationLimits {
+    /// Nesting of conditions and variables
+    pub max_depth: u32,
+    pub max_external_queries: u32,
+    pub max_expression_tokens: u32,
+    /// Conditions and variables evaluated in total
+    pub max_steps: u32,
+}
+
+impl Default for EvaluationLimits {
+    fn default() -> Self {
+        Self {
+            max_depth: 32,
+            max_external_queries: 20,
+            max_expression_tokens: 100,
+            max_steps: 1000,
+        }
+    }
+}
+
+#[cw_serde]
+pub struct ProxyConfig {
+    pub target: Addr,
+    /// Top level message types that can be forwarded, any type when not set
+    pub allowed_msgs: Option<Vec<String>>,
+}
+
+#[cw_serde]
+pub struct Escrow {
+    pub depositor: Addr,
+    pub recipient: Addr,
+    pub funds: Vec<Coin>,
+    /// Named condition that releases the funds to the recipient
+    pub condition_name: String,
+    /// Named condition that allows a refund before expiry
+    pub refund_condition_name: Option<String>,
+    pub expiry: Expiration,
+}
+
+#[cw_serde]
+pub struct Trigger {
+    pub condition_name: String,
+    /// Dispatched by the contract whenever the trigger fires
+    pub msgs: Vec<CosmosMsg>,
+    /// Paid from the trigger balance to whoever fires it
+    pub reward: Vec<Coin>,
+    /// Removes the trigger after it fires once
+    pub one_shot: bool,
+}
+
+#[cw_serde]
+pub struct TriggerInfo {
+    pub creator: Addr,
+    pub trigger: Trigger,
+    /// Funds deposited with the trigger to pay out rewards
+    pub balance: Vec<Coin>,
+}
+
+/// Something to evaluate in an `EvaluateBatch` query
+#[cw_serde]
+pub enum BatchItem {
+    /// A stored condition, the default condition when no name is given
+    Condition {
+        name: Option<String>,
+    },
+    CustomCondition {
+        condition: Condition,
+    },
+    Variable {
+        name: String,
+    },
+    CustomVariable {
+        variable: Variable,
+    },
 }
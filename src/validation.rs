@@ -0,0 +1,303 @@
+use cosmwasm_std::{Deps, Int128};
+
+use crate::{
+    dependencies::{expression_dependencies, variable_dependencies},
+    error::ContractError,
+    msg::Diagnostic,
+    packages::eval::eval::{check_syntax, Tokens},
+    query::parse_path_checked,
+    state::VARIABLES,
+    template::{template_placeholders, Placeholder},
+    types::{
+        Condition, ConditionCompare, ConditionWing, ExternalQueryMsg, StorageKeyType, Variable,
+    },
+};
+
+/// What a condition wing is known to resolve to without evaluating it
+enum Operand {
+    Number,
+    Text,
+    Bool,
+    /// A variable, only known once it is resolved
+    Unknown,
+}
+
+/// Errors found without evaluating, each with the path of the field that raised it
+type Problems = Vec<(String, ContractError)>;
+
+/// Problems found in a condition without evaluating it, empty when it is well formed
+pub fn condition_diagnostics(deps: &Deps, condition: &Condition) -> Vec<Diagnostic> {
+    diagnostics(condition_problems(deps, condition))
+}
+
+/// Problems found in a variable without evaluating it, empty when it is well formed
+pub fn variable_diagnostics(deps: &Deps, variable: &Variable) -> Vec<Diagnostic> {
+    diagnostics(variable_problems(deps, variable))
+}
+
+/// Fails with the first problem found in a condition
+pub fn check_condition_valid(deps: &Deps, condition: &Condition) -> Result<(), ContractError> {
+    first_problem(condition_problems(deps, condition))
+}
+
+/// Fails with the first problem found in a variable
+pub fn check_variable_valid(deps: &Deps, variable: &Variable) -> Result<(), ContractError> {
+    first_problem(variable_problems(deps, variable))
+}
+
+fn condition_problems(deps: &Deps, condition: &Condition) -> Problems {
+    let mut problems = vec![];
+    check_condition(deps, "", condition, &mut problems);
+    problems
+}
+
+fn variable_problems(deps: &Deps, variable: &Variable) -> Problems {
+    let mut problems = vec![];
+    check_variable(deps, "", variable, &mut problems);
+    problems
+}
+
+fn diagnostics(problems: Problems) -> Vec<Diagnostic> {
+    problems
+        .into_iter()
+        .map(|(path, err)| Diagnostic {
+            path,
+            message: err.to_string(),
+        })
+        .collect()
+}
+
+fn first_problem(problems: Problems) -> Result<(), ContractError> {
+    match problems.into_iter().next() {
+        Some((_, err)) => Err(err),
+        None => Ok(()),
+    }
+}
+
+fn push(problems: &mut Problems, path: &str, err: ContractError) {
+    problems.push((path.to_string(), err));
+}
+
+fn join(path: &str, field: &str) -> String {
+    if path.is_empty() {
+        field.to_string()
+    } else {
+        format!("{path}.{field}")
+    }
+}
+
+fn check_condition(deps: &Deps, path: &str, condition: &Condition, problems: &mut Problems) {
+    let mut operands = vec![];
+    for (field, wing) in [("left", &condition.left), ("right", &condition.right)] {
+        let path = join(path, field);
+        match wing {
+            ConditionWing::Expression(tokens) => check_expression(
+                deps,
+                &path,
+                tokens,
+                expression_dependencies(deps.storage, tokens),
+                problems,
+            ),
+            ConditionWing::Condition(condition) => {
+                check_condition(deps, &path, condition, problems)
+            }
+            _ => {}
+        }
+        operands.push(operand(deps, wing));
+    }
+    let ordered = !matches!(
+        condition.compare,
+        ConditionCompare::Eq | ConditionCompare::Neq
+    );
+    // Only numbers can be ordered, anything else fails with an invalid operator
+    if ordered
+        && operands
+            .iter()
+            .any(|operand| matches!(operand, Operand::Text | Operand::Bool))
+    {
+        push(problems, path, ContractError::InvalidOperator {});
+    }
+}
+
+fn operand(deps: &Deps, wing: &ConditionWing) -> Operand {
+    match wing {
+        ConditionWing::Number(_) => Operand::Number,
+        ConditionWing::String(value) => literal(value),
+        ConditionWing::Bool(_) | ConditionWing::Condition(_) => Operand::Bool,
+        ConditionWing::Expression(tokens) => match tokens.as_slice() {
+            [token] if VARIABLES.has(deps.storage, token) => Operand::Unknown,
+            [token] => literal(token),
+            _ => Operand::Number,
+        },
+    }
+}
+
+fn literal(value: &str) -> Operand {
+    if value.parse::<Int128>().is_ok() {
+        Operand::Number
+    } else {
+        Operand::Text
+    }
+}
+
+/// `names` are the variables the expression reads, which differ between conditions and
+/// variables
+fn check_expression(
+    deps: &Deps,
+    path: &str,
+    tokens: &Tokens,
+    names: Vec<String>,
+    problems: &mut Problems,
+) {
+    if tokens.is_empty() {
+        return push(problems, path, ContractError::EmptyExpression {});
+    }
+    if let Err(msg) = check_syntax(tokens) {
+        push(
+            problems,
+            path,
+            ContractError::InvalidExpression {
+                msg: msg.to_string(),
+            },
+        );
+    }
+    for name in names {
+        if !VARIABLES.has(deps.storage, &name) {
+            push(problems, path, ContractError::UndefinedName { name });
+        }
+    }
+}
+
+fn check_variable(deps: &Deps, path: &str, variable: &Variable, problems: &mut Problems) {
+    match variable {
+        Variable::Query(query) => {
+            check_address(deps, &join(path, "contract"), &query.contract, problems);
+            if let Some(result) = &query.result {
+                if let Err(err) = parse_path_checked(result) {
+                    push(problems, &join(path, "result"), err);
+                }
+            }
+            let path = join(path, "query");
+            match &query.query {
+                ExternalQueryMsg::Template(msg) => match template_placeholders(&msg.msg) {
+                    Ok(placeholders) => {
+                        for (_, placeholder) in placeholders {
+                            match placeholder {
+                                Placeholder::Var(name) if !VARIABLES.has(deps.storage, &name) => {
+                                    push(problems, &path, ContractError::UndefinedName { name })
+                                }
+                                Placeholder::Ref(reference) => {
+                                    if let Err(err) = parse_path_checked(&reference) {
+                                        push(problems, &path, err)
+                                    }
+                                }
+                                Placeholder::Var(_) => {}
+                            }
+                        }
+                    }
+                    Err(err) => push(problems, &path, err),
+                },
+                ExternalQueryMsg::Storage(msg) => {
+                    for (i, key) in msg.keys.iter().enumerate() {
+                        let path = join(&path, &format!("keys.{i}"));
+                        match key.key_type {
+                            StorageKeyType::Addr => {
+                                check_address(deps, &path, &key.value, problems)
+                            }
+                            _ => check_variable(deps, &path, &key.value, problems),
+                        }
+                    }
+                }
+                ExternalQueryMsg::Raw(_) | ExternalQueryMsg::Smart(_) => {}
+            }
+        }
+        Variable::Expression(tokens) => check_expression(
+            deps,
+            path,
+            tokens,
+            variable_dependencies(deps.storage, variable),
+            problems,
+        ),
+        Variable::Coalesce(variables) => {
+            if variables.is_empty() {
+                push(problems, path, ContractError::EmptyCoalesce {});
+            }
+            for (i, variable) in variables.iter().enumerate() {
+                check_variable(deps, &join(path, &i.to_string()), variable, problems);
+            }
+        }
+        Variable::WithDefault { variable, .. } => {
+            check_variable(deps, &join(path, "variable"), variable, problems)
+        }
+        Variable::Balance { address, .. } | Variable::AllBalances { address } => {
+            check_address(deps, &join(path, "address"), address, problems)
+        }
+        Variable::Supply { .. } => {}
+        Variable::Cw20Balance { token, address } => {
+            check_address(deps, &join(path, "token"), token, problems);
+            check_address(deps, &join(path, "address"), address, problems);
+        }
+        Variable::Cw20Allowance {
+            token,
+            owner,
+            spender,
+        } => {
+            check_address(deps, &join(path, "token"), token, problems);
+            check_address(deps, &join(path, "owner"), owner, problems);
+            check_address(deps, &join(path, "spender"), spender, problems);
+        }
+        Variable::Cw20TokenInfo { token, .. } => {
+            check_address(deps, &join(path, "token"), token, problems)
+        }
+        Variable::Cw721OwnerOf {
+            collection,
+            token_id,
+        } => {
+            check_address(deps, &join(path, "collection"), collection, problems);
+            check_variable(deps, &join(path, "token_id"), token_id, problems);
+        }
+        Variable::Cw721Tokens { collection, owner } => {
+            check_address(deps, &join(path, "collection"), collection, problems);
+            check_address(deps, &join(path, "owner"), owner, problems);
+        }
+        Variable::Cw721IsApproved {
+            collection,
+            token_id,
+            spender,
+        } => {
+            check_address(deps, &join(path, "collection"), collection, problems);
+            check_variable(deps, &join(path, "token_id"), token_id, problems);
+            check_address(deps, &join(path, "spender"), spender, problems);
+        }
+        Variable::Delegation {
+            delegator,
+            validator,
+        } => {
+            check_address(deps, &join(path, "delegator"), delegator, problems);
+            check_variable(deps, &join(path, "validator"), validator, problems);
+        }
+        Variable::TotalDelegated { delegator } => {
+            check_address(deps, &join(path, "delegator"), delegator, problems)
+        }
+        Variable::BondedDenom => {}
+        Variable::IsActiveValidator { validator } => {
+            check_variable(deps, &join(path, "validator"), validator, problems)
+        }
+        Variable::Raw(_) => {}
+        Variable::Reference(reference) => {
+            if let Err(err) = parse_path_checked(reference) {
+                push(problems, path, err);
+            }
+        }
+    }
+}
+
+/// Raw addresses are known upfront, anything else is only known when evaluated
+fn check_address(deps: &Deps, path: &str, variable: &Variable, problems: &mut Problems) {
+    if let Variable::Raw(address) = variable {
+        if let Err(err) = deps.api.addr_validate(address) {
+            push(problems, path, err.into());
+        }
+    }
+    check_variable(deps, path, variable, problems)
+}